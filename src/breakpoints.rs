@@ -0,0 +1,193 @@
+use crate::gcode;
+use crate::simple_machine::ToolState;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StateField {
+    X,
+    Y,
+    Z,
+    E,
+    Feedrate,
+    Velocity,
+    Time,
+    Tool,
+}
+
+impl StateField {
+    fn parse(name: &str) -> Option<StateField> {
+        match name.to_lowercase().as_str() {
+            "x" => Some(StateField::X),
+            "y" => Some(StateField::Y),
+            "z" => Some(StateField::Z),
+            "e" => Some(StateField::E),
+            "f" | "feed" | "feedrate" => Some(StateField::Feedrate),
+            "v" | "velocity" => Some(StateField::Velocity),
+            "time" => Some(StateField::Time),
+            "t" | "tool" => Some(StateField::Tool),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self, state: &ToolState) -> f32 {
+        match self {
+            StateField::X => state.x,
+            StateField::Y => state.y,
+            StateField::Z => state.z,
+            StateField::E => state.e,
+            StateField::Feedrate => state.feedrate,
+            StateField::Velocity => state.velocity,
+            StateField::Time => state.time,
+            StateField::Tool => state.tool as f32,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Condition {
+    // Before the block with this label runs
+    Block(i32),
+    // Before a block with this word runs, any number when None
+    Word(char, Option<i32>),
+    // Once the state field drops below or rises above the value
+    Below(StateField, f32),
+    Above(StateField, f32),
+}
+
+impl Condition {
+    // "N12", "M6", "T", "Z<-1.5" or "feed>1000"
+    pub fn parse(text: &str) -> Option<Condition> {
+        for (separator, below) in [('<', true), ('>', false)].iter() {
+            if let Some(index) = text.find(*separator) {
+                let field = StateField::parse(text[..index].trim())?;
+                let value = text[index + 1..].trim().parse().ok()?;
+                return Some(if *below {
+                    Condition::Below(field, value)
+                } else {
+                    Condition::Above(field, value)
+                });
+            }
+        }
+
+        let mut chars = text.trim().chars();
+        let command = chars.next()?.to_ascii_uppercase();
+        let number = chars.as_str();
+        match (command, number.is_empty()) {
+            ('N', false) => Some(Condition::Block(number.parse().ok()?)),
+            ('N', true) => None,
+            (_, true) if command.is_ascii_alphabetic() => Some(Condition::Word(command, None)),
+            (_, false) if command.is_ascii_alphabetic() => {
+                Some(Condition::Word(command, Some(number.parse().ok()?)))
+            }
+            _ => None,
+        }
+    }
+
+    // Breakpoints stop before a block, watchpoints after one
+    pub fn is_watchpoint(&self) -> bool {
+        matches!(self, Condition::Below(..) | Condition::Above(..))
+    }
+
+    pub fn matches_block(&self, label: i32, block: &gcode::GCodeBlock) -> bool {
+        match self {
+            Condition::Block(line) => *line == label,
+            Condition::Word(command, major) => block.iter().any(|word| {
+                word.command == *command
+                    && match major {
+                        Some(major) => word.major == *major,
+                        None => true,
+                    }
+            }),
+            _ => false,
+        }
+    }
+
+    pub fn matches_state(&self, state: &ToolState) -> bool {
+        match self {
+            Condition::Below(field, value) => field.value(state) < *value,
+            Condition::Above(field, value) => field.value(state) > *value,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Block(line) => write!(f, "N{}", line),
+            Condition::Word(command, Some(major)) => write!(f, "{}{}", command, major),
+            Condition::Word(command, None) => write!(f, "{}", command),
+            Condition::Below(field, value) => write!(f, "{:?} < {:.3}", field, value),
+            Condition::Above(field, value) => write!(f, "{:?} > {:.3}", field, value),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub condition: Condition,
+    pub enabled: bool,
+    // Watchpoints fire when their condition becomes true, not while it stays so
+    pub(crate) active: bool,
+}
+
+impl Breakpoint {
+    pub fn new(id: usize, condition: Condition) -> Self {
+        Breakpoint {
+            id,
+            condition,
+            enabled: true,
+            active: false,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.condition.is_watchpoint() {
+            "Watchpoint"
+        } else {
+            "Breakpoint"
+        };
+        write!(f, "{} {} at {}", kind, self.id, self.condition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_conditions() {
+        assert_eq!(Some(Condition::Block(12)), Condition::parse("N12"));
+        assert_eq!(Some(Condition::Word('M', Some(6))), Condition::parse("m6"));
+        assert_eq!(Some(Condition::Word('T', None)), Condition::parse("T"));
+        assert_eq!(
+            Some(Condition::Below(StateField::Z, -1.5)),
+            Condition::parse("Z<-1.5")
+        );
+        assert_eq!(
+            Some(Condition::Above(StateField::Feedrate, 1000.0)),
+            Condition::parse("feed > 1000")
+        );
+        assert_eq!(None, Condition::parse("q<1"));
+        assert_eq!(None, Condition::parse("N"));
+        assert_eq!(None, Condition::parse("1"));
+    }
+
+    #[test]
+    fn test_match_conditions() {
+        let (_, block) = gcode::parse_line(3, "T2 M6").unwrap();
+        assert!(Condition::Word('M', Some(6)).matches_block(3, &block));
+        assert!(Condition::Word('T', None).matches_block(3, &block));
+        assert!(!Condition::Word('M', Some(3)).matches_block(3, &block));
+        assert!(Condition::Block(3).matches_block(3, &block));
+
+        let mut state = ToolState::new();
+        state.z = -2.0;
+        assert!(Condition::Below(StateField::Z, -1.0).matches_state(&state));
+        assert!(!Condition::Above(StateField::Z, -1.0).matches_state(&state));
+        assert!(!Condition::Block(3).matches_state(&state));
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Time spent in a block, split by what the machine was doing
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlockTime {
+    pub block: i32,
+    pub tool: i32,
+    pub motion: f32,
+    pub dwell: f32,
+    pub tool_change: f32,
+}
+
+impl BlockTime {
+    pub fn total(&self) -> f32 {
+        self.motion + self.dwell + self.tool_change
+    }
+}
+
+// Cycle time of a program, accumulated per block as it is executed
+#[derive(Debug, PartialEq, Clone)]
+pub struct CycleTimeEstimate {
+    pub blocks: Vec<BlockTime>,
+}
+
+impl CycleTimeEstimate {
+    pub fn new() -> Self {
+        CycleTimeEstimate { blocks: vec![] }
+    }
+
+    fn entry(&mut self, block: i32, tool: i32) -> &mut BlockTime {
+        let continued = match self.blocks.last() {
+            Some(last) => last.block == block && last.tool == tool,
+            None => false,
+        };
+        if !continued {
+            self.blocks.push(BlockTime {
+                block,
+                tool,
+                motion: 0.0,
+                dwell: 0.0,
+                tool_change: 0.0,
+            });
+        }
+        self.blocks.last_mut().unwrap()
+    }
+
+    pub fn add_motion(&mut self, block: i32, tool: i32, seconds: f32) {
+        self.entry(block, tool).motion += seconds;
+    }
+
+    pub fn add_dwell(&mut self, block: i32, tool: i32, seconds: f32) {
+        self.entry(block, tool).dwell += seconds;
+    }
+
+    pub fn add_tool_change(&mut self, block: i32, tool: i32, seconds: f32) {
+        self.entry(block, tool).tool_change += seconds;
+    }
+
+    pub fn per_tool(&self) -> BTreeMap<i32, f32> {
+        let mut tools = BTreeMap::new();
+        for block in self.blocks.iter() {
+            *tools.entry(block.tool).or_insert(0.0) += block.total();
+        }
+        tools
+    }
+
+    pub fn total(&self) -> f32 {
+        self.blocks.iter().map(|block| block.total()).sum()
+    }
+}
+
+impl Default for CycleTimeEstimate {
+    fn default() -> Self {
+        CycleTimeEstimate::new()
+    }
+}
+
+impl fmt::Display for CycleTimeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Cycle time per block:")?;
+        for block in self.blocks.iter() {
+            writeln!(
+                f,
+                "  N{} T{}: {:.3} s (motion {:.3}, dwell {:.3}, tool change {:.3})",
+                block.block,
+                block.tool,
+                block.total(),
+                block.motion,
+                block.dwell,
+                block.tool_change
+            )?;
+        }
+        writeln!(f, "Cycle time per tool:")?;
+        for (tool, seconds) in self.per_tool() {
+            writeln!(f, "  T{}: {:.3} s", tool, seconds)?;
+        }
+        write!(f, "Total cycle time: {:.3} s", self.total())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulate_blocks() {
+        let mut estimate = CycleTimeEstimate::new();
+        estimate.add_motion(1, 0, 1.5);
+        estimate.add_motion(1, 0, 0.5);
+        estimate.add_tool_change(2, 3, 5.0);
+        estimate.add_dwell(3, 3, 0.25);
+        estimate.add_motion(4, 3, 2.0);
+
+        assert_eq!(4, estimate.blocks.len());
+        assert_eq!(2.0, estimate.blocks[0].motion);
+        assert_eq!(5.0, estimate.blocks[1].total());
+        assert_eq!(9.25, estimate.total());
+
+        let tools = estimate.per_tool();
+        assert_eq!(Some(&2.0), tools.get(&0));
+        assert_eq!(Some(&7.25), tools.get(&3));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::breakpoints::Breakpoint;
+use crate::gcode;
+use crate::history::Snapshot;
+use crate::simple_machine::{
+    MachineStatus, PlannedSegment, SoftLimitViolation, ToolConfig, ToolState,
+};
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MachineError {
+    SoftLimit(SoftLimitViolation),
+    Unsupported(gcode::GCodeBlock),
+    // Stopped in the block on a soft limit
+    Halted(i32),
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MachineError::SoftLimit(violation) => write!(f, "Soft limit violation: {}", violation),
+            MachineError::Unsupported(words) => write!(f, "Unsupported: {:?}", words),
+            MachineError::Halted(block) => write!(f, "Halted on soft limit in block N{}", block),
+        }
+    }
+}
+
+// Consumer of what the machine does while running a program. Every event has
+// a default that ignores it, so a sink only implements what it cares about.
+pub trait MachineSink {
+    // Configuration when the sink is added, and whenever it changes
+    fn config(&mut self, _toolconfig: &ToolConfig) {}
+
+    // A single step on axis 0-3 (X, Y, Z, E), `time` seconds since program start
+    fn step(&mut self, _axis: usize, _direction: i32, _time: f64) {}
+
+    // Path velocity in units/min reached at `time` seconds since program start
+    fn progress(&mut self, _velocity: f32, _time: f32) {}
+
+    fn segment(&mut self, _segment: &PlannedSegment) {}
+
+    // State once `block` has been executed
+    fn state(&mut self, _block: i32, _state: &ToolState) {}
+
+    fn message(&mut self, _block: i32, _message: &str) {}
+
+    fn error(&mut self, _block: i32, _error: &MachineError) {}
+
+    // Stopped by a breakpoint before `block`, or a watchpoint after it
+    fn stopped(&mut self, _block: i32, _breakpoint: &Breakpoint) {}
+
+    // Moved back or forward in the recorded run, events continue from `snapshot`
+    fn jumped(&mut self, _snapshot: &Snapshot) {}
+
+    fn finished(&mut self, _status: MachineStatus) {}
+}
+
+// Lets the caller keep a handle on a sink the machine owns
+impl<T: MachineSink> MachineSink for Rc<RefCell<T>> {
+    fn config(&mut self, toolconfig: &ToolConfig) {
+        self.borrow_mut().config(toolconfig);
+    }
+
+    fn step(&mut self, axis: usize, direction: i32, time: f64) {
+        self.borrow_mut().step(axis, direction, time);
+    }
+
+    fn progress(&mut self, velocity: f32, time: f32) {
+        self.borrow_mut().progress(velocity, time);
+    }
+
+    fn segment(&mut self, segment: &PlannedSegment) {
+        self.borrow_mut().segment(segment);
+    }
+
+    fn state(&mut self, block: i32, state: &ToolState) {
+        self.borrow_mut().state(block, state);
+    }
+
+    fn message(&mut self, block: i32, message: &str) {
+        self.borrow_mut().message(block, message);
+    }
+
+    fn error(&mut self, block: i32, error: &MachineError) {
+        self.borrow_mut().error(block, error);
+    }
+
+    fn stopped(&mut self, block: i32, breakpoint: &Breakpoint) {
+        self.borrow_mut().stopped(block, breakpoint);
+    }
+
+    fn jumped(&mut self, snapshot: &Snapshot) {
+        self.borrow_mut().jumped(snapshot);
+    }
+
+    fn finished(&mut self, status: MachineStatus) {
+        self.borrow_mut().finished(status);
+    }
+}
+
+// Prints messages, errors, plans and states as they happen
+pub struct ConsoleSink;
+
+impl MachineSink for ConsoleSink {
+    fn segment(&mut self, segment: &PlannedSegment) {
+        println!("Planned {}", segment);
+    }
+
+    fn state(&mut self, block: i32, state: &ToolState) {
+        println!("Sync: N{} state: {:?}", block, state);
+    }
+
+    fn message(&mut self, _block: i32, message: &str) {
+        println!("{}", message);
+    }
+
+    fn error(&mut self, _block: i32, error: &MachineError) {
+        println!("{}", error);
+    }
+
+    fn stopped(&mut self, block: i32, breakpoint: &Breakpoint) {
+        println!("{} hit in block N{}", breakpoint, block);
+    }
+
+    fn jumped(&mut self, snapshot: &Snapshot) {
+        println!(
+            "Jumped to N{} state: {:?} modal: {:?}",
+            snapshot.block, snapshot.state, snapshot.modal
+        );
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    Config(ToolConfig),
+    Step(usize, i32, f64),
+    Progress(f32, f32),
+    Segment(PlannedSegment),
+    State(i32, ToolState),
+    Message(i32, String),
+    Error(i32, MachineError),
+    Stopped(i32, usize),
+    Jumped(i32, ToolState),
+    Finished(MachineStatus),
+}
+
+// Keeps every event, steps and progress only when asked to
+pub struct Recorder {
+    pub events: Vec<Event>,
+    motion: bool,
+}
+
+impl Recorder {
+    pub fn new(motion: bool) -> Self {
+        Recorder {
+            events: vec![],
+            motion,
+        }
+    }
+}
+
+impl MachineSink for Recorder {
+    fn config(&mut self, toolconfig: &ToolConfig) {
+        self.events.push(Event::Config(toolconfig.clone()));
+    }
+
+    fn step(&mut self, axis: usize, direction: i32, time: f64) {
+        if self.motion {
+            self.events.push(Event::Step(axis, direction, time));
+        }
+    }
+
+    fn progress(&mut self, velocity: f32, time: f32) {
+        if self.motion {
+            self.events.push(Event::Progress(velocity, time));
+        }
+    }
+
+    fn segment(&mut self, segment: &PlannedSegment) {
+        self.events.push(Event::Segment(segment.clone()));
+    }
+
+    fn state(&mut self, block: i32, state: &ToolState) {
+        self.events.push(Event::State(block, state.clone()));
+    }
+
+    fn message(&mut self, block: i32, message: &str) {
+        self.events.push(Event::Message(block, message.to_string()));
+    }
+
+    fn error(&mut self, block: i32, error: &MachineError) {
+        self.events.push(Event::Error(block, error.clone()));
+    }
+
+    fn stopped(&mut self, block: i32, breakpoint: &Breakpoint) {
+        self.events.push(Event::Stopped(block, breakpoint.id));
+    }
+
+    fn jumped(&mut self, snapshot: &Snapshot) {
+        self.events
+            .push(Event::Jumped(snapshot.block, snapshot.state.clone()));
+    }
+
+    fn finished(&mut self, status: MachineStatus) {
+        self.events.push(Event::Finished(status));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_machine::{SimpleMachine, ToolConfig};
+
+    #[test]
+    fn test_multiple_sinks() {
+        let everything = Rc::new(RefCell::new(Recorder::new(true)));
+        let blocks = Rc::new(RefCell::new(Recorder::new(false)));
+
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.add_sink(Box::new(everything.clone()));
+        machine.add_sink(Box::new(blocks.clone()));
+        machine.load_str("G1 X1 Y0.5 F600\nG5\n");
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+
+        let steps = |axis| {
+            everything
+                .borrow()
+                .events
+                .iter()
+                .filter(|event| matches!(event, Event::Step(a, 1, _) if *a == axis))
+                .count()
+        };
+        assert_eq!(100, steps(0));
+        assert_eq!(50, steps(1));
+
+        // Both sinks see the same blocks, only one of them the motion
+        let blocks = &blocks.borrow().events;
+        assert!(blocks
+            .iter()
+            .all(|event| !matches!(event, Event::Step(..) | Event::Progress(..))));
+        assert!(matches!(blocks[0], Event::Config(_)));
+        assert!(matches!(blocks[1], Event::Message(0, _)));
+        assert!(matches!(blocks[2], Event::Segment(_)));
+        assert!(matches!(&blocks[3], Event::State(0, state) if state.x == 1.0));
+        assert!(matches!(
+            blocks[4],
+            Event::Error(1, MachineError::Unsupported(_))
+        ));
+        assert_eq!(
+            Some(&Event::Finished(MachineStatus::Finished)),
+            blocks.last()
+        );
+    }
+}
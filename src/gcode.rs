@@ -108,9 +108,28 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_empty_blocks() {
+        assert_eq!(None, parse_line(0, "(sdfsdf)"));
+        assert_eq!(None, parse_line(0, "N10 (only a number)"));
+        assert_eq!(None, parse_line(0, "  "));
+        let program = parse_str("(first)\nG1 X1\n\n(second)\nG1 X2\n");
+        let mut blocks: Vec<&i32> = program.keys().collect();
+        blocks.sort();
+        assert_eq!(vec![&0, &1], blocks);
+    }
+
+    #[test]
+    fn test_listing() {
+        let listing = listing("; setup\nG1 X1\n\nN20 G1 X2 (pass two)\nG1 X3\n");
+        let blocks: Vec<Option<i32>> = listing.iter().map(|line| line.block).collect();
+        assert_eq!(vec![None, Some(0), None, Some(20), Some(2)], blocks);
+        assert_eq!("N20 G1 X2 (pass two)", listing[3].text);
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct GCode {
     pub command: char,
     pub major: i32,
@@ -120,11 +139,19 @@ pub struct GCode {
 pub type GCodeBlock = Vec<GCode>;
 pub type GCodeProgram = HashMap<i32, GCodeBlock>;
 
+// Line of the program text, with the label of the block parsed from it
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLine {
+    pub block: Option<i32>,
+    pub text: String,
+}
+pub type Listing = Vec<SourceLine>;
+
 pub fn parse_line(linenumber: i32, line: &str) -> Option<(i32, GCodeBlock)> {
     // Detect comments
-    let cleaned_line = match line.split(|c| c == ';' || c == '%').next() {
+    let cleaned_line = match line.split([';', '%']).next() {
         Some(section) => {
-            if section.len() != 0 {
+            if !section.is_empty() {
                 let mut comment_scope = 0;
                 let mut cleaned_section: String = "".to_string();
                 for c in section.chars() {
@@ -159,18 +186,12 @@ pub fn parse_line(linenumber: i32, line: &str) -> Option<(i32, GCodeBlock)> {
                 // Both must be valid
                 let parameter = match token.get(0..1) {
                     Some(name) => {
-                        let command = match name.chars().next() {
-                            Some(command) => command,
-                            None => ' ',
-                        };
+                        let command = name.chars().next().unwrap_or(' ');
                         match token.get(1..) {
                             Some(value) => {
                                 let mut parts = value.split('.');
                                 let major = match parts.next() {
-                                    Some(v) => match v.parse::<i32>() {
-                                        Ok(v) => v,
-                                        Err(_) => 0,
-                                    },
+                                    Some(v) => v.parse::<i32>().unwrap_or_default(),
                                     None => 0,
                                 };
                                 let minor = match parts.next() {
@@ -185,9 +206,9 @@ pub fn parse_line(linenumber: i32, line: &str) -> Option<(i32, GCodeBlock)> {
                                 };
 
                                 Some(GCode {
-                                    command: command,
-                                    major: major,
-                                    minor: minor,
+                                    command,
+                                    major,
+                                    minor,
                                     text_value: None,
                                 })
                             }
@@ -197,19 +218,20 @@ pub fn parse_line(linenumber: i32, line: &str) -> Option<(i32, GCodeBlock)> {
                     None => None,
                 };
 
-                match parameter {
-                    Some(parameter) => {
-                        if first_token && parameter.command == 'N' {
-                            first_token = false;
-                            // An N-command was stated, override the default linenumber
-                            validated_linenumber = parameter.major as i32;
-                        } else {
-                            gcodeblock.push(parameter);
-                        }
+                if let Some(parameter) = parameter {
+                    if first_token && parameter.command == 'N' {
+                        first_token = false;
+                        // An N-command was stated, override the default linenumber
+                        validated_linenumber = parameter.major;
+                    } else {
+                        gcodeblock.push(parameter);
                     }
-                    None => (),
                 }
             }
+            // Nothing but a comment or a line number
+            if gcodeblock.is_empty() {
+                return None;
+            }
             Some((validated_linenumber, gcodeblock))
         }
         None => None,
@@ -219,19 +241,41 @@ pub fn parse_line(linenumber: i32, line: &str) -> Option<(i32, GCodeBlock)> {
 pub fn parse(filepath: String) -> GCodeProgram {
     let contents =
         std::fs::read_to_string(filepath).expect("Something went wrong reading the file");
+    parse_str(&contents)
+}
 
+pub fn parse_str(contents: &str) -> GCodeProgram {
     let mut program = GCodeProgram::new();
 
     let mut linenumber = 0;
     for line in contents.lines() {
-        match parse_line(linenumber as i32, line) {
-            Some((line, gcode)) => {
-                program.insert(line, gcode);
+        if let Some((line, gcode)) = parse_line(linenumber, line) {
+            program.insert(line, gcode);
+            linenumber += 1;
+        }
+    }
+
+    program
+}
+
+// Every line of the program text, comments and blank lines included
+pub fn listing(contents: &str) -> Listing {
+    let mut listing = Listing::new();
+
+    let mut linenumber = 0;
+    for line in contents.lines() {
+        let block = match parse_line(linenumber, line) {
+            Some((line, _)) => {
                 linenumber += 1;
+                Some(line)
             }
-            None => (),
-        }
+            None => None,
+        };
+        listing.push(SourceLine {
+            block,
+            text: line.to_string(),
+        });
     }
 
-    return program;
+    listing
 }
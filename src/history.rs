@@ -0,0 +1,289 @@
+use crate::simple_machine::{
+    Coolant, DistanceMode, MotionMode, PathMode, Plane, Spindle, ToolState, Units,
+};
+use crate::stepgen::StepRateMonitor;
+
+// Modal settings in effect, carried from block to block
+#[derive(Debug, PartialEq, Clone)]
+pub struct ModalState {
+    pub motion_mode: MotionMode,
+    pub plane: Plane,
+    pub units: Units,
+    pub distance_mode: DistanceMode,
+    pub path_mode: PathMode,
+    // Selected by T, in the spindle only after M6
+    pub selected_tool: i32,
+    // Programmed S, rev/min
+    pub spindle_speed: f32,
+    pub spindle: Spindle,
+    pub coolant: Coolant,
+}
+
+// The machine at the start of a block, enough to carry on from there
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub block: i32,
+    pub state: ToolState,
+    pub modal: ModalState,
+    pub(crate) halted: bool,
+    pub(crate) ended: bool,
+    pub(crate) entry_velocity: Option<f32>,
+    // A copy per block, the monitor is a few fixed size arrays
+    pub(crate) step_rates: StepRateMonitor,
+    // How much of each result had been recorded by then
+    pub(crate) samples: usize,
+    pub(crate) planned: usize,
+    pub(crate) timed_blocks: usize,
+    pub(crate) violations: usize,
+}
+
+// Longest stretch of a block one sample stands for, so playback moves the
+// tool along straight moves too
+const SAMPLE_INTERVAL: f64 = 0.01;
+
+// Distance of `point` from the line through `from` and `to`
+pub(crate) fn distance_to_line(point: &[f32; 4], from: &[f32; 4], to: &[f32; 4]) -> f32 {
+    let line = [to[0] - from[0], to[1] - from[1], to[2] - from[2]];
+    let offset = [point[0] - from[0], point[1] - from[1], point[2] - from[2]];
+    let length = (line[0] * line[0] + line[1] * line[1] + line[2] * line[2]).sqrt();
+    if length == 0.0 {
+        return (offset[0] * offset[0] + offset[1] * offset[1] + offset[2] * offset[2]).sqrt();
+    }
+    let cross = [
+        offset[1] * line[2] - offset[2] * line[1],
+        offset[2] * line[0] - offset[0] * line[2],
+        offset[0] * line[1] - offset[1] * line[0],
+    ];
+    (cross[0] * cross[0] + cross[1] * cross[1] + cross[2] * cross[2]).sqrt() / length
+}
+
+// Tool position after a step of `block`, seconds since program start
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MotionSample {
+    pub time: f64,
+    pub block: i32,
+    pub position: [f32; 4],
+    // Planned velocity there, mm/min
+    pub velocity: f32,
+}
+
+// Timeline of a run, a snapshot before every executed block and after the
+// last one, plus the positions the tool stepped through
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    snapshots: Vec<Snapshot>,
+    motion: Vec<MotionSample>,
+    current: usize,
+    // Positions the last sample stands for besides its own, left out of the motion
+    passed: Vec<[f32; 4]>,
+    // How far the left out positions may be from the line between the samples
+    tolerance: f32,
+    // Steps recorded since the history was created, however many samples
+    // they took and whatever was dropped by going back
+    recorded_steps: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            snapshots: vec![],
+            motion: vec![],
+            current: 0,
+            passed: vec![],
+            tolerance: 0.0,
+            recorded_steps: 0,
+        }
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    pub fn motion(&self) -> &[MotionSample] {
+        &self.motion
+    }
+
+    pub fn recorded_steps(&self) -> usize {
+        self.recorded_steps
+    }
+
+    // Snapshot the machine is at, it is past the last one while a block executes
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    // Seconds from program start to the end of the last executed block
+    pub fn end_time(&self) -> f32 {
+        match self.snapshots.last() {
+            Some(snapshot) => snapshot.state.time,
+            None => 0.0,
+        }
+    }
+
+    // First snapshot before `block`
+    pub fn find_block(&self, block: i32) -> Option<usize> {
+        self.snapshots
+            .iter()
+            .position(|snapshot| snapshot.block == block)
+    }
+
+    // Snapshot before the block executing at `time`
+    pub fn find_time(&self, time: f32) -> Option<usize> {
+        self.snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.state.time <= time)
+    }
+
+    pub(crate) fn record(&mut self, snapshot: Snapshot) {
+        self.current = self.snapshots.len();
+        self.snapshots.push(snapshot);
+        self.passed.clear();
+    }
+
+    pub(crate) fn set_tolerance(&mut self, tolerance: f32) {
+        self.tolerance = tolerance;
+    }
+
+    // Steps of several axes at the same time make a single sample. Within a
+    // block a step also takes the place of the last sample while the positions
+    // that sample stood for stay within the tolerance of the line to it, for
+    // at most SAMPLE_INTERVAL. A straight move keeps a sample per interval
+    // rather than per step, 32 bytes each.
+    pub(crate) fn record_motion(
+        &mut self,
+        time: f64,
+        block: i32,
+        position: [f32; 4],
+        velocity: f32,
+    ) {
+        self.recorded_steps += 1;
+        let sample = MotionSample {
+            time,
+            block,
+            position,
+            velocity,
+        };
+        if let Some(last) = self.motion.last_mut() {
+            if last.time == time && last.block == block {
+                *last = sample;
+                return;
+            }
+        }
+        if self.stays_on_line(&sample) {
+            let last = self.motion.last_mut().unwrap();
+            self.passed.push(last.position);
+            *last = sample;
+        } else {
+            self.passed.clear();
+            self.motion.push(sample);
+        }
+    }
+
+    // Whether `sample` can stand for the last sample too, the one before that
+    // still in its block. Checking a few of the positions passed over keeps
+    // this quick, the middle one is the furthest off an arc.
+    fn stays_on_line(&self, sample: &MotionSample) -> bool {
+        let block_start = self.snapshots.last().map_or(0, |snapshot| snapshot.samples);
+        let count = self.motion.len();
+        if count < block_start + 2 {
+            return false;
+        }
+        let (from, last) = (&self.motion[count - 2], &self.motion[count - 1]);
+        if from.block != sample.block
+            || last.block != sample.block
+            || sample.time - from.time > SAMPLE_INTERVAL
+        {
+            return false;
+        }
+        let passed = &self.passed;
+        (0..4)
+            .filter_map(|quarter| passed.get(passed.len() * quarter / 4))
+            .chain(std::iter::once(&last.position))
+            .all(|point| {
+                distance_to_line(point, &from.position, &sample.position) <= self.tolerance
+            })
+    }
+
+    pub(crate) fn set_current(&mut self, index: usize) {
+        self.current = index;
+    }
+
+    // Executing from an earlier snapshot forgets everything after it,
+    // returns the snapshot when anything was dropped
+    pub(crate) fn branch(&mut self) -> Option<Snapshot> {
+        if self.current + 1 >= self.snapshots.len() {
+            return None;
+        }
+        self.snapshots.truncate(self.current + 1);
+        let snapshot = self.snapshots[self.current].clone();
+        self.motion.truncate(snapshot.samples);
+        self.passed.clear();
+        Some(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SAMPLE_INTERVAL;
+    use crate::simple_machine::{MachineStatus, SimpleMachine, ToolConfig};
+
+    #[test]
+    fn test_timeline() {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G1 X10 F600\nG4 P2\nT2 M6\nG1 X0\n");
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+
+        let history = machine.history();
+        let blocks: Vec<i32> = history.snapshots().iter().map(|s| s.block).collect();
+        assert_eq!(vec![0, 1, 2, 3, 4], blocks);
+        assert_eq!(4, history.current());
+        assert_eq!(2000, history.recorded_steps());
+        // A sample every hundredth of a second along the straight moves
+        let motion = history.motion();
+        assert!(motion.len() < 250);
+        assert_eq!([0.0, 0.0, 0.0, 0.0], motion[motion.len() - 1].position);
+        assert_eq!(machine.toolstate().time, history.end_time());
+
+        // The dwell starts when the first move ends
+        let dwell = history.snapshots()[1].state.time;
+        assert_eq!(Some(1), history.find_time(dwell + 1.0));
+        assert_eq!(Some(0), history.find_time(dwell - 0.01));
+        assert_eq!(Some(2), history.find_block(2));
+        assert_eq!(None, history.find_block(7));
+        assert_eq!(2, history.snapshots()[3].modal.selected_tool);
+    }
+
+    #[test]
+    fn test_diagonal_motion() {
+        // X and Y step together, one sample for both
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G1 X10 Y10 F600\n");
+        machine.run_to_end();
+        let motion = machine.history().motion();
+        assert!(motion
+            .iter()
+            .all(|sample| sample.position[0] == sample.position[1]));
+        assert!(motion
+            .windows(2)
+            .all(|pair| pair[1].time - pair[0].time <= SAMPLE_INTERVAL + 0.001));
+        assert_eq!([10.0, 10.0, 0.0, 0.0], motion[motion.len() - 1].position);
+    }
+
+    #[test]
+    fn test_arc_samples() {
+        // The chords between samples stay within a step or two of the arc
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G2 X20 I10 F1200\n");
+        machine.run_to_end();
+        let history = machine.history();
+        let motion = history.motion();
+        assert!(motion.len() * 10 < history.recorded_steps());
+        for pair in motion.windows(2) {
+            let middle = [
+                0.5 * (pair[0].position[0] + pair[1].position[0]) - 10.0,
+                0.5 * (pair[0].position[1] + pair[1].position[1]),
+            ];
+            assert!(middle[0].hypot(middle[1]) > 10.0 - 0.02);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+pub mod breakpoints;
+pub mod estimate;
+pub mod events;
+pub mod gcode;
+pub mod history;
+pub mod planner;
+pub mod render;
+pub mod simple_machine;
+pub mod stepgen;
+pub mod tools;
+pub mod vcd;
+pub mod view;
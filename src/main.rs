@@ -1,33 +1,342 @@
-mod gcode;
-mod simple_machine;
+#[cfg(feature = "gui")]
 mod window;
+use gcode_simulator::breakpoints::Condition;
+use gcode_simulator::events::ConsoleSink;
+use gcode_simulator::render;
+use gcode_simulator::simple_machine::TravelLimits;
+use gcode_simulator::stepgen::StepRateMonitor;
+use gcode_simulator::tools::ToolTable;
+use gcode_simulator::view::{Preset, View};
+use gcode_simulator::{planner, simple_machine};
 use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+#[cfg(feature = "gui")]
 use std::sync::mpsc;
-use std::thread;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let (toolstate_tx, toolstate_rx) = mpsc::channel::<simple_machine::SyncEntry>();
-    let (config_tx, config_rx) = mpsc::channel::<simple_machine::ToolConfig>();
+struct Arguments {
+    filepath: String,
+    toolconfig: simple_machine::ToolConfig,
+    vcd_path: Option<String>,
+    tool_table: Option<String>,
+    breakpoints: Vec<Condition>,
+    overrides: simple_machine::Overrides,
+    #[cfg(feature = "gui")]
+    window: window::WindowSettings,
+    image: ImageSettings,
+}
+
+// Pictures of the finished run instead of the window
+#[derive(Debug, Clone)]
+struct ImageSettings {
+    png: Option<String>,
+    svg: Option<String>,
+    size: (u32, u32),
+    preset: Preset,
+    // Numbered PNGs into a directory and an animated GIF, a frame every
+    // `interval` seconds of machine time
+    frames: Option<String>,
+    gif: Option<String>,
+    interval: f32,
+}
+
+impl ImageSettings {
+    fn headless(&self) -> bool {
+        self.png.is_some() || self.svg.is_some() || self.frames.is_some() || self.gif.is_some()
+    }
+}
+
+// "800x600"
+fn parse_size(text: &str) -> Option<(u32, u32)> {
+    let (width, height) = text.split_once('x')?;
+    let size = (width.parse().ok()?, height.parse().ok()?);
+    if size.0 == 0 || size.1 == 0 {
+        return None;
+    }
+    Some(size)
+}
+
+// Program path followed by optional machine settings, signal dump path,
+// breakpoints, overrides, window settings and images to render
+fn parse_arguments(args: &[String]) -> Option<Arguments> {
+    let mut filepath = None;
+    let mut toolconfig = simple_machine::ToolConfig::new();
+    let mut vcd_path = None;
+    let mut tool_table = None;
+    let mut breakpoints = vec![];
+    let mut overrides = simple_machine::Overrides::new();
+    #[cfg(feature = "gui")]
+    let mut window = window::WindowSettings::default();
+    let mut image = ImageSettings {
+        png: None,
+        svg: None,
+        size: (800, 600),
+        preset: Preset::Top,
+        frames: None,
+        gif: None,
+        interval: 1.0,
+    };
+
+    let mut arguments = args.iter().skip(1);
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--junction-deviation" => {
+                let deviation = arguments.next()?.parse().ok()?;
+                toolconfig.junction = planner::JunctionMode::Deviation(deviation);
+            }
+            "--junction-jerk" => toolconfig.junction = planner::JunctionMode::Jerk,
+            "--lookahead" => toolconfig.lookahead = arguments.next()?.parse().ok()?,
+            "--vcd" => vcd_path = Some(arguments.next()?.to_string()),
+            "--tool-table" => tool_table = Some(arguments.next()?.to_string()),
+            "--travel-x" => toolconfig.travel_x = TravelLimits::parse(arguments.next()?)?,
+            "--travel-y" => toolconfig.travel_y = TravelLimits::parse(arguments.next()?)?,
+            "--travel-z" => toolconfig.travel_z = TravelLimits::parse(arguments.next()?)?,
+            "--halt-on-soft-limit" => toolconfig.halt_on_soft_limit = true,
+            "--step-pulse" => {
+                let microseconds: f32 = arguments.next()?.parse().ok()?;
+                toolconfig.step_pulse_width = microseconds * 1e-6;
+            }
+            "--dir-setup" => {
+                let microseconds: f32 = arguments.next()?.parse().ok()?;
+                toolconfig.dir_setup_time = microseconds * 1e-6;
+            }
+            "--break" => breakpoints.push(Condition::parse(arguments.next()?)?),
+            "--feed-override" => overrides.feed = arguments.next()?.parse().ok()?,
+            "--rapid-override" => overrides.rapid = arguments.next()?.parse().ok()?,
+            "--spindle-override" => overrides.spindle = arguments.next()?.parse().ok()?,
+            #[cfg(feature = "gui")]
+            "--ui-scale" => window.ui_scale = Some(arguments.next()?.parse().ok()?),
+            #[cfg(feature = "gui")]
+            "--fullscreen" => window.fullscreen = true,
+            #[cfg(feature = "gui")]
+            "--font" => window.font = Some(arguments.next()?.to_string()),
+            "--png" => image.png = Some(arguments.next()?.to_string()),
+            "--svg" => image.svg = Some(arguments.next()?.to_string()),
+            "--image-size" => image.size = parse_size(arguments.next()?)?,
+            "--view" => image.preset = Preset::parse(arguments.next()?)?,
+            "--frames" => image.frames = Some(arguments.next()?.to_string()),
+            "--gif" => image.gif = Some(arguments.next()?.to_string()),
+            "--frame-interval" => {
+                image.interval = arguments.next()?.parse().ok().filter(|t| *t > 0.0)?
+            }
+            _ => filepath = Some(argument.to_string()),
+        }
+    }
+    Some(Arguments {
+        filepath: filepath?,
+        toolconfig,
+        vcd_path,
+        tool_table,
+        breakpoints,
+        overrides,
+        #[cfg(feature = "gui")]
+        window,
+        image,
+    })
+}
+
+// Sets up the machine with the console and optionally a signal dump listening
+fn start_machine(mut arguments: Arguments) -> Option<simple_machine::SimpleMachine> {
+    if let Some(path) = &arguments.tool_table {
+        let table = match std::fs::read_to_string(path) {
+            Ok(contents) => ToolTable::parse(&contents),
+            Err(error) => Err(error.to_string()),
+        };
+        match table {
+            Ok(table) => arguments.toolconfig.tools = table,
+            Err(error) => {
+                println!("Unable to read tool table {}: {}", path, error);
+                return None;
+            }
+        }
+    }
+    let mut machine = simple_machine::SimpleMachine::new(arguments.toolconfig);
+    machine.set_overrides(arguments.overrides);
+    if let Err(error) = machine.load_file(&arguments.filepath) {
+        println!("Unable to read {}: {}", arguments.filepath, error);
+        return None;
+    }
+    machine.add_sink(Box::new(ConsoleSink));
+    if let Some(path) = arguments.vcd_path {
+        if let Err(error) = machine.dump_signals(&path) {
+            println!("Unable to write signal dump {}: {}", path, error);
+        }
+    }
+    for condition in arguments.breakpoints {
+        machine.add_breakpoint(condition);
+    }
+    Some(machine)
+}
+
+// Runs the program to the end and writes the pictures asked for
+fn render_images(machine: &mut simple_machine::SimpleMachine, image: &ImageSettings) {
+    machine.run_to_end();
+    let (width, height) = image.size;
+    let mut view = View::new(width, height);
+    view.set_preset(image.preset);
+    view.fit(&machine.program_bounds(), width, height);
+
+    if let Some(path) = &image.png {
+        let canvas = render::render_run(machine, &view, width, height);
+        let result =
+            File::create(path).and_then(|file| canvas.write_png(&mut BufWriter::new(file)));
+        match result {
+            Ok(()) => println!("Wrote {}", path),
+            Err(error) => println!("Unable to write {}: {}", path, error),
+        }
+    }
+    if let Some(path) = &image.svg {
+        let result = File::create(path).and_then(|file| {
+            render::write_svg(machine, &view, width, height, &mut BufWriter::new(file))
+        });
+        match result {
+            Ok(()) => println!("Wrote {}", path),
+            Err(error) => println!("Unable to write {}: {}", path, error),
+        }
+    }
+    if let Some(directory) = &image.frames {
+        match write_frames(machine, &view, image, directory) {
+            Ok(count) => println!("Wrote {} frame(s) to {}", count, directory),
+            Err(error) => println!("Unable to write {}: {}", directory, error),
+        }
+    }
+    if let Some(path) = &image.gif {
+        match write_gif(machine, &view, image, path) {
+            Ok(count) => println!("Wrote {} frame(s) to {}", count, path),
+            Err(error) => println!("Unable to write {}: {}", path, error),
+        }
+    }
+}
 
-    let threads = match args.get(1) {
-        Some(argument) => {
-            let filepath = argument.to_string();
+// frame00000.png, frame00001.png and so on
+fn write_frames(
+    machine: &simple_machine::SimpleMachine,
+    view: &View,
+    image: &ImageSettings,
+    directory: &str,
+) -> io::Result<usize> {
+    std::fs::create_dir_all(directory)?;
+    let mut number = 0;
+    let (width, height) = image.size;
+    render::render_frames(
+        machine,
+        view,
+        width,
+        height,
+        image.interval,
+        &mut |canvas| {
+            let path = Path::new(directory).join(format!("frame{:05}.png", number));
+            number += 1;
+            canvas.write_png(&mut BufWriter::new(File::create(path)?))
+        },
+    )
+}
 
-            simple_machine::start_machine(filepath, toolstate_tx, config_tx)
+// A tenth of a second per frame, the finished run held for two seconds
+fn write_gif(
+    machine: &simple_machine::SimpleMachine,
+    view: &View,
+    image: &ImageSettings,
+    path: &str,
+) -> io::Result<usize> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let (width, height) = image.size;
+    let mut gif = render::GifWriter::new(&mut out, width, height)?;
+    let mut last = None;
+    let count = render::render_frames(
+        machine,
+        view,
+        width,
+        height,
+        image.interval,
+        &mut |canvas| {
+            if let Some(previous) = last.replace(canvas.clone()) {
+                gif.frame(&previous, 10)?;
+            }
+            Ok(())
+        },
+    )?;
+    if let Some(last) = last {
+        gif.frame(&last, 200)?;
+    }
+    gif.finish()?;
+    Ok(count)
+}
+
+fn report(machine: &simple_machine::SimpleMachine) {
+    if machine.status() == simple_machine::MachineStatus::Running {
+        println!("Stopped in block N{}", machine.current_block());
+    }
+
+    println!("Planned segments:");
+    for segment in machine.planned_segments() {
+        println!("  {}", segment);
+    }
+    println!("{}", machine.cycle_time());
+    report_step_rates(machine.step_rates());
+
+    let violations = machine.soft_limit_violations();
+    if !violations.is_empty() {
+        println!("{} soft limit violation(s):", violations.len());
+        for violation in violations {
+            println!("  {}", violation);
         }
+    }
+}
+
+fn report_step_rates(step_rates: &StepRateMonitor) {
+    println!("Step rates:");
+    let limits = step_rates.limits();
+    for (axis, name) in ['X', 'Y', 'Z', 'E'].iter().enumerate() {
+        println!(
+            "  {}: max {:.0} Hz, limit {:.0} Hz, {} step(s) over limit",
+            name, step_rates.max_rate[axis], limits[axis], step_rates.violations[axis]
+        );
+    }
+}
+
+// The window drives the machine from here
+#[cfg(feature = "gui")]
+fn run_window(machine: &mut simple_machine::SimpleMachine, settings: window::WindowSettings) {
+    let (gui_tx, gui_rx) = mpsc::channel::<window::GuiUpdate>();
+    machine.add_sink(Box::new(window::GuiSink::new(gui_tx)));
+    window::setup_window(machine, gui_rx, settings);
+}
+
+// Built without the window there is only the report of the whole run
+#[cfg(not(feature = "gui"))]
+fn run_window(machine: &mut simple_machine::SimpleMachine) {
+    println!("Built without the window, running to the end");
+    machine.run_to_end();
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let arguments = match parse_arguments(&args) {
+        Some(arguments) => arguments,
         None => {
             println!("Unable to parse arguments: {:?}", &args);
-            vec![]
+            return;
         }
     };
+    #[cfg(feature = "gui")]
+    let settings = arguments.window.clone();
+    let image = arguments.image.clone();
 
-    let gui_thread_handle = thread::spawn(move || {
-        window::setup_window(toolstate_rx, config_rx);
-    });
-
-    for thread_handle in threads {
-        thread_handle.join().expect("Thread failed!");
+    if let Some(mut machine) = start_machine(arguments) {
+        if image.headless() {
+            render_images(&mut machine, &image);
+        } else {
+            #[cfg(feature = "gui")]
+            run_window(&mut machine, settings);
+            #[cfg(not(feature = "gui"))]
+            run_window(&mut machine);
+        }
+        report(&machine);
+        if let Err(error) = machine.close_signal_dump() {
+            println!("Signal dump failed: {}", error);
+        }
     }
-    gui_thread_handle.join().expect("Thread failed!");
 }
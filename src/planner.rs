@@ -0,0 +1,443 @@
+// Motion planning, turns a programmed move into a velocity profile bounded by
+// the dynamics of every axis taking part in it. The profiles are trapezoidal,
+// acceleration is constant or zero and jerk is not modelled.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AxisDynamics {
+    // units/s
+    pub max_velocity: f32,
+    // units/s^2
+    pub max_acceleration: f32,
+    // Largest change of velocity taken in an instant rather than by
+    // accelerating, at a corner or from standstill, units/s. Marlin calls it
+    // jerk, it does not limit the rate of change of acceleration.
+    pub max_velocity_jump: f32,
+}
+
+impl AxisDynamics {
+    pub fn new(max_velocity: f32, max_acceleration: f32, max_velocity_jump: f32) -> Self {
+        AxisDynamics {
+            max_velocity,
+            max_acceleration,
+            max_velocity_jump,
+        }
+    }
+
+    fn unbounded() -> Self {
+        AxisDynamics::new(f32::MAX, f32::MAX, f32::MAX)
+    }
+
+    // Limits of a move along the unit vector `direction` (x, y, z, e)
+    pub fn along(axes: &[AxisDynamics; 4], direction: [f32; 4]) -> AxisDynamics {
+        let mut limits = AxisDynamics::unbounded();
+        for (axis, component) in axes.iter().zip(direction.iter()) {
+            let component = component.abs();
+            if component > 0.0 {
+                limits = limits.combined(&AxisDynamics::new(
+                    axis.max_velocity / component,
+                    axis.max_acceleration / component,
+                    axis.max_velocity_jump / component,
+                ));
+            }
+        }
+        limits
+    }
+
+    pub fn combined(&self, other: &AxisDynamics) -> AxisDynamics {
+        AxisDynamics::new(
+            self.max_velocity.min(other.max_velocity),
+            self.max_acceleration.min(other.max_acceleration),
+            self.max_velocity_jump.min(other.max_velocity_jump),
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VelocityProfile {
+    pub length: f32,
+    pub entry_velocity: f32,
+    pub cruise_velocity: f32,
+    pub exit_velocity: f32,
+    pub acceleration: f32,
+    pub accelerate_distance: f32,
+    pub decelerate_distance: f32,
+}
+
+impl VelocityProfile {
+    pub fn trapezoidal(
+        length: f32,
+        entry_velocity: f32,
+        nominal_velocity: f32,
+        exit_velocity: f32,
+        acceleration: f32,
+    ) -> Self {
+        if length <= 0.0 || nominal_velocity <= 0.0 {
+            return VelocityProfile {
+                length: 0.0,
+                entry_velocity: 0.0,
+                cruise_velocity: 0.0,
+                exit_velocity: 0.0,
+                acceleration,
+                accelerate_distance: 0.0,
+                decelerate_distance: 0.0,
+            };
+        }
+
+        // Entry and exit must be reachable from each other within the segment
+        let reachable = |from: f32| (from * from + 2.0 * acceleration * length).sqrt();
+        let entry = entry_velocity.min(nominal_velocity);
+        let exit = exit_velocity.min(nominal_velocity).min(reachable(entry));
+        let entry = entry.min(reachable(exit));
+
+        let mut cruise = nominal_velocity;
+        let mut accelerate_distance = (cruise * cruise - entry * entry) / (2.0 * acceleration);
+        let mut decelerate_distance = (cruise * cruise - exit * exit) / (2.0 * acceleration);
+
+        if accelerate_distance + decelerate_distance > length {
+            // Triangular profile, the nominal velocity is never reached
+            accelerate_distance = ((exit * exit - entry * entry) / (2.0 * acceleration) + length)
+                .max(0.0)
+                .min(2.0 * length)
+                / 2.0;
+            decelerate_distance = length - accelerate_distance;
+            cruise = (entry * entry + 2.0 * acceleration * accelerate_distance).sqrt();
+        }
+
+        VelocityProfile {
+            length,
+            entry_velocity: entry,
+            cruise_velocity: cruise,
+            exit_velocity: exit,
+            acceleration,
+            accelerate_distance,
+            decelerate_distance,
+        }
+    }
+
+    fn accelerate_time(&self) -> f32 {
+        (self.cruise_velocity - self.entry_velocity) / self.acceleration
+    }
+
+    fn cruise_time(&self) -> f32 {
+        let cruise_distance = self.length - self.accelerate_distance - self.decelerate_distance;
+        if cruise_distance > 0.0 {
+            cruise_distance / self.cruise_velocity
+        } else {
+            0.0
+        }
+    }
+
+    fn decelerate_time(&self) -> f32 {
+        (self.cruise_velocity - self.exit_velocity) / self.acceleration
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.accelerate_time() + self.cruise_time() + self.decelerate_time()
+    }
+
+    pub fn velocity_at(&self, distance: f32) -> f32 {
+        let distance = distance.max(0.0).min(self.length);
+        if distance < self.accelerate_distance {
+            (self.entry_velocity * self.entry_velocity + 2.0 * self.acceleration * distance).sqrt()
+        } else if distance > self.length - self.decelerate_distance {
+            let remaining = self.length - distance;
+            (self.exit_velocity * self.exit_velocity + 2.0 * self.acceleration * remaining).sqrt()
+        } else {
+            self.cruise_velocity
+        }
+    }
+
+    pub fn time_at(&self, distance: f32) -> f32 {
+        let distance = distance.max(0.0).min(self.length);
+        let velocity = self.velocity_at(distance);
+        if distance < self.accelerate_distance {
+            (velocity - self.entry_velocity) / self.acceleration
+        } else if distance > self.length - self.decelerate_distance {
+            self.accelerate_time()
+                + self.cruise_time()
+                + (self.cruise_velocity - velocity) / self.acceleration
+        } else {
+            self.accelerate_time() + (distance - self.accelerate_distance) / self.cruise_velocity
+        }
+    }
+}
+
+// A planned move as seen by the look-ahead, directions are unit vectors (x, y, z, e)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Segment {
+    pub length: f32,
+    pub entry_direction: [f32; 4],
+    pub exit_direction: [f32; 4],
+    pub nominal_velocity: f32,
+    pub dynamics: AxisDynamics,
+}
+
+impl Segment {
+    // `feedrate` in units/s, bounded by the dynamics along the segment
+    pub fn new(
+        length: f32,
+        entry_direction: [f32; 4],
+        exit_direction: [f32; 4],
+        feedrate: f32,
+        dynamics: AxisDynamics,
+    ) -> Self {
+        Segment {
+            length,
+            entry_direction,
+            exit_direction,
+            nominal_velocity: feedrate.min(dynamics.max_velocity),
+            dynamics,
+        }
+    }
+
+    pub fn profile(&self, entry_velocity: f32, exit_velocity: f32) -> VelocityProfile {
+        VelocityProfile::trapezoidal(
+            self.length,
+            entry_velocity,
+            self.nominal_velocity,
+            exit_velocity,
+            self.dynamics.max_acceleration,
+        )
+    }
+
+    fn reachable_entry(&self, exit_velocity: f32) -> f32 {
+        (exit_velocity * exit_velocity + 2.0 * self.dynamics.max_acceleration * self.length).sqrt()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JunctionMode {
+    // Grbl style, corners are taken as an arc that deviates at most this far from the path
+    Deviation(f32),
+    // Marlin style, every axis may change velocity instantly by up to its
+    // max_velocity_jump
+    Jerk,
+}
+
+impl JunctionMode {
+    // Velocity a segment may start from or come to rest at
+    pub fn standstill_velocity(&self, segment: &Segment) -> f32 {
+        match self {
+            JunctionMode::Deviation(_) => 0.0,
+            JunctionMode::Jerk => segment
+                .dynamics
+                .max_velocity_jump
+                .min(segment.nominal_velocity),
+        }
+    }
+
+    pub fn junction_velocity(
+        &self,
+        axes: &[AxisDynamics; 4],
+        previous: &Segment,
+        next: &Segment,
+    ) -> f32 {
+        let velocity = match self {
+            JunctionMode::Deviation(deviation) => {
+                let cos_theta: f32 = -previous
+                    .exit_direction
+                    .iter()
+                    .zip(next.entry_direction.iter())
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>();
+                if cos_theta < -0.999_999 {
+                    // Straight continuation
+                    f32::MAX
+                } else if cos_theta > 0.999_999 {
+                    // Full reversal
+                    0.0
+                } else {
+                    let sin_theta_d2 = (0.5 * (1.0 - cos_theta)).sqrt();
+                    (next.dynamics.max_acceleration * deviation * sin_theta_d2
+                        / (1.0 - sin_theta_d2))
+                        .sqrt()
+                }
+            }
+            JunctionMode::Jerk => {
+                let mut velocity = f32::MAX;
+                for (axis, (from, to)) in axes.iter().zip(
+                    previous
+                        .exit_direction
+                        .iter()
+                        .zip(next.entry_direction.iter()),
+                ) {
+                    let change = (to - from).abs();
+                    if change > 0.0 {
+                        velocity = velocity.min(axis.max_velocity_jump / change);
+                    }
+                }
+                velocity
+            }
+        };
+        velocity
+            .min(previous.nominal_velocity)
+            .min(next.nominal_velocity)
+    }
+
+    // Highest velocity the first segment of the look-ahead buffer may exit at, so
+    // that every following segment can still be planned and the last one can stop
+    pub fn exit_velocity(
+        &self,
+        axes: &[AxisDynamics; 4],
+        entry_velocity: f32,
+        segments: &[Segment],
+    ) -> f32 {
+        let last = match segments.last() {
+            Some(last) => last,
+            None => return 0.0,
+        };
+
+        let mut exit = self.standstill_velocity(last);
+        for index in (1..segments.len()).rev() {
+            let junction = self.junction_velocity(axes, &segments[index - 1], &segments[index]);
+            exit = junction.min(segments[index].reachable_entry(exit));
+        }
+
+        let first = &segments[0];
+        let accelerated = (entry_velocity * entry_velocity
+            + 2.0 * first.dynamics.max_acceleration * first.length)
+            .sqrt();
+        exit.min(accelerated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 1e-3,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_trapezoidal_profile() {
+        // 0 -> 10 units/s at 10 units/s^2 takes 5 units and 1 s each way
+        let profile = VelocityProfile::trapezoidal(20.0, 0.0, 10.0, 0.0, 10.0);
+
+        assert_close(5.0, profile.accelerate_distance);
+        assert_close(5.0, profile.decelerate_distance);
+        assert_close(10.0, profile.cruise_velocity);
+        assert_close(3.0, profile.duration());
+        assert_close(10.0, profile.velocity_at(10.0));
+        assert_close(1.0, profile.time_at(5.0));
+        assert_close(1.5, profile.time_at(10.0));
+        assert_close(3.0, profile.time_at(20.0));
+    }
+
+    #[test]
+    fn test_triangular_profile() {
+        let profile = VelocityProfile::trapezoidal(4.0, 0.0, 10.0, 0.0, 10.0);
+
+        assert_close(2.0, profile.accelerate_distance);
+        assert_close(2.0, profile.decelerate_distance);
+        assert_close(40.0_f32.sqrt(), profile.cruise_velocity);
+        assert_close(2.0 * 40.0_f32.sqrt() / 10.0, profile.duration());
+    }
+
+    #[test]
+    fn test_unreachable_exit_velocity() {
+        // Accelerating from standstill over 5 units only reaches 10 units/s
+        let profile = VelocityProfile::trapezoidal(5.0, 0.0, 20.0, 20.0, 10.0);
+
+        assert_close(10.0, profile.exit_velocity);
+        assert_close(1.0, profile.duration());
+    }
+
+    #[test]
+    fn test_dynamics_along_direction() {
+        let axes = [
+            AxisDynamics::new(100.0, 1000.0, 10.0),
+            AxisDynamics::new(50.0, 1000.0, 10.0),
+            AxisDynamics::new(10.0, 100.0, 1.0),
+            AxisDynamics::new(100.0, 1000.0, 10.0),
+        ];
+
+        let limits = AxisDynamics::along(&axes, [1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(AxisDynamics::new(100.0, 1000.0, 10.0), limits);
+
+        let limits = AxisDynamics::along(&axes, [0.6, 0.8, 0.0, 0.0]);
+        assert_close(62.5, limits.max_velocity);
+        assert_close(1250.0, limits.max_acceleration);
+        assert_close(12.5, limits.max_velocity_jump);
+
+        let segment = Segment::new(
+            100.0,
+            [0.6, 0.8, 0.0, 0.0],
+            [0.6, 0.8, 0.0, 0.0],
+            1000.0,
+            limits,
+        );
+        assert_close(62.5, segment.nominal_velocity);
+        assert_close(12.5, JunctionMode::Jerk.standstill_velocity(&segment));
+    }
+
+    fn straight(direction: [f32; 4], length: f32) -> Segment {
+        Segment::new(
+            length,
+            direction,
+            direction,
+            100.0,
+            AxisDynamics::new(100.0, 1000.0, 10.0),
+        )
+    }
+
+    #[test]
+    fn test_junction_deviation() {
+        let axes = [AxisDynamics::new(100.0, 1000.0, 10.0); 4];
+        let mode = JunctionMode::Deviation(0.01);
+        let x = straight([1.0, 0.0, 0.0, 0.0], 10.0);
+        let y = straight([0.0, 1.0, 0.0, 0.0], 10.0);
+        let back = straight([-1.0, 0.0, 0.0, 0.0], 10.0);
+
+        assert_close(100.0, mode.junction_velocity(&axes, &x, &x));
+        assert_close(0.0, mode.junction_velocity(&axes, &x, &back));
+
+        // 90 degree corner: sin(theta/2) = sqrt(0.5)
+        let sin_theta_d2 = 0.5_f32.sqrt();
+        let expected = (1000.0 * 0.01 * sin_theta_d2 / (1.0 - sin_theta_d2)).sqrt();
+        assert_close(expected, mode.junction_velocity(&axes, &x, &y));
+    }
+
+    #[test]
+    fn test_junction_jerk() {
+        let axes = [AxisDynamics::new(100.0, 1000.0, 10.0); 4];
+        let x = straight([1.0, 0.0, 0.0, 0.0], 10.0);
+        let y = straight([0.0, 1.0, 0.0, 0.0], 10.0);
+        let back = straight([-1.0, 0.0, 0.0, 0.0], 10.0);
+
+        assert_close(10.0, JunctionMode::Jerk.junction_velocity(&axes, &x, &y));
+        assert_close(5.0, JunctionMode::Jerk.junction_velocity(&axes, &x, &back));
+    }
+
+    #[test]
+    fn test_lookahead_exit_velocity() {
+        let axes = [AxisDynamics::new(100.0, 1000.0, 10.0); 4];
+        let mode = JunctionMode::Deviation(0.01);
+        let x = straight([1.0, 0.0, 0.0, 0.0], 10.0);
+        let short = straight([1.0, 0.0, 0.0, 0.0], 0.5);
+
+        // Nothing follows, come to a stop
+        assert_close(0.0, mode.exit_velocity(&axes, 0.0, &[x]));
+
+        // A long straight continuation allows full speed through the junction
+        assert_close(100.0, mode.exit_velocity(&axes, 100.0, &[x, x]));
+
+        // A short final segment limits how fast we may enter it: v^2 = 2 * a * d
+        assert_close(
+            (2.0_f32 * 1000.0 * 0.5).sqrt(),
+            mode.exit_velocity(&axes, 100.0, &[x, short]),
+        );
+
+        // Starting from standstill only 10 units of acceleration are available
+        assert_close(100.0, mode.exit_velocity(&axes, 0.0, &[x, x, x]));
+        assert_close(
+            (2.0_f32 * 1000.0 * 0.5).sqrt(),
+            mode.exit_velocity(&axes, 0.0, &[short, x]),
+        );
+    }
+}
@@ -0,0 +1,1164 @@
+use crate::history::{distance_to_line, MotionSample};
+use crate::simple_machine;
+use crate::simple_machine::{Bounds, SimpleMachine};
+use crate::tools::ToolTable;
+use crate::view::View;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Colour {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Colour {
+    pub const BLACK: Colour = Colour::rgb(0, 0, 0);
+    pub const WHITE: Colour = Colour::rgb(255, 255, 255);
+    pub const RED: Colour = Colour::rgb(255, 0, 0);
+    pub const MAGENTA: Colour = Colour::rgb(255, 0, 255);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Colour { r, g, b }
+    }
+}
+
+// Straight line between two pixels
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Line {
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+    pub colour: Colour,
+}
+
+// Executed position, machine coordinates
+#[derive(Debug, PartialEq)]
+struct TrailPoint {
+    x: f32,
+    y: f32,
+    z: f32,
+    block: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Colouring {
+    MoveKind,
+    // Cutting moves from yellow at the top of the program to purple at its deepest
+    Depth,
+}
+
+fn lerp_colour(from: Colour, to: Colour, t: f32) -> Colour {
+    let channel = |a: u8, b: u8| (a as f32 + t * (b as f32 - a as f32)).round() as u8;
+    Colour::rgb(
+        channel(from.r, to.r),
+        channel(from.g, to.g),
+        channel(from.b, to.b),
+    )
+}
+
+fn kind_colour(kind: simple_machine::MoveKind) -> Colour {
+    match kind {
+        simple_machine::MoveKind::Rapid => Colour::rgb(170, 170, 170),
+        simple_machine::MoveKind::Feed => Colour::rgb(30, 80, 220),
+        simple_machine::MoveKind::Arc => Colour::rgb(0, 160, 170),
+        simple_machine::MoveKind::Plunge => Colour::rgb(240, 120, 0),
+        simple_machine::MoveKind::Retract => Colour::rgb(150, 190, 0),
+    }
+}
+
+// The executed toolpath as lines coloured by the kind of move or by depth,
+// the tool on top of it. Steps that stay within a couple of steps of a
+// straight line make a single piece of the trail.
+pub struct ToolTrail {
+    trail: Vec<TrailPoint>,
+    // Recorded motion samples already in the trail
+    followed: usize,
+    // Sample the last piece of the trail starts from
+    piece_start: usize,
+    // How far a sample may be off the piece it is merged into
+    tolerance: f32,
+    tool: simple_machine::ToolState,
+    tools: ToolTable,
+    // The program's toolpath, one of its blocks highlighted when picked
+    toolpath: Vec<simple_machine::PathSegment>,
+    highlight: Option<i32>,
+    kinds: HashMap<i32, simple_machine::MoveKind>,
+    // Top and bottom of the program
+    depth: (f32, f32),
+    colouring: Colouring,
+    // Size of the axes and tool marker relative to a 1080 line display
+    ui_scale: f32,
+}
+
+impl ToolTrail {
+    pub fn new(machine: &simple_machine::SimpleMachine) -> Self {
+        let toolpath = machine.toolpath();
+        let kinds = toolpath
+            .iter()
+            .map(|segment| (segment.block, segment.kind))
+            .collect();
+        let bounds = machine.program_bounds();
+        let toolconfig = machine.toolconfig();
+        let steps_per_unit = toolconfig
+            .steps_per_unit_x
+            .min(toolconfig.steps_per_unit_y)
+            .min(toolconfig.steps_per_unit_z)
+            .max(1);
+        ToolTrail {
+            trail: vec![],
+            followed: 0,
+            piece_start: 0,
+            tolerance: 2.0 / steps_per_unit as f32,
+            tool: simple_machine::ToolState::new(),
+            tools: machine.toolconfig().tools.clone(),
+            toolpath,
+            highlight: None,
+            kinds,
+            depth: (bounds.max[2], bounds.min[2]),
+            colouring: Colouring::MoveKind,
+            ui_scale: 1.0,
+        }
+    }
+
+    pub fn set_ui_scale(&mut self, ui_scale: f32) {
+        self.ui_scale = ui_scale;
+    }
+
+    pub fn tool(&self) -> &simple_machine::ToolState {
+        &self.tool
+    }
+
+    pub fn set_tool(&mut self, tool: &simple_machine::ToolState) {
+        self.tool = tool.clone();
+    }
+
+    pub fn toggle_colouring(&mut self) {
+        self.colouring = match self.colouring {
+            Colouring::MoveKind => Colouring::Depth,
+            Colouring::Depth => Colouring::MoveKind,
+        };
+    }
+
+    pub fn set_tools(&mut self, tools: &ToolTable) {
+        self.tools = tools.clone();
+    }
+
+    pub fn set_highlight(&mut self, block: Option<i32>) {
+        self.highlight = block;
+    }
+
+    // Middle of the path of `block`
+    pub fn block_center(&self, block: i32) -> Option<[f32; 3]> {
+        let mut points = self
+            .toolpath
+            .iter()
+            .filter(|segment| segment.block == block)
+            .flat_map(|segment| segment.points.iter());
+        let mut bounds = Bounds::new(*points.next()?);
+        for point in points {
+            bounds.extend(*point);
+        }
+        Some([
+            0.5 * (bounds.min[0] + bounds.max[0]),
+            0.5 * (bounds.min[1] + bounds.max[1]),
+            0.5 * (bounds.min[2] + bounds.max[2]),
+        ])
+    }
+
+    // Block of the toolpath closest to the pixel `x`, `y`, if within `tolerance` pixels
+    pub fn pick(&self, view: &View, x: f32, y: f32, tolerance: f32) -> Option<i32> {
+        let mut closest = None;
+        let mut closest_distance = tolerance;
+        for segment in self.toolpath.iter() {
+            for pair in segment.points.windows(2) {
+                let from = view.screen_point(pair[0][0], pair[0][1], pair[0][2]);
+                let to = view.screen_point(pair[1][0], pair[1][1], pair[1][2]);
+                let line = (to.0 - from.0, to.1 - from.1);
+                let length = line.0 * line.0 + line.1 * line.1;
+                let t = if length > 0.0 {
+                    (((x - from.0) * line.0 + (y - from.1) * line.1) / length).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                let distance = (from.0 + t * line.0 - x).hypot(from.1 + t * line.1 - y);
+                if distance <= closest_distance {
+                    closest = Some(segment.block);
+                    closest_distance = distance;
+                }
+            }
+        }
+        closest
+    }
+
+    // Whether sample `index` carries on the last piece of the trail, the
+    // samples it passes over staying close to the piece. Checking a few of
+    // them keeps this quick, the middle one is the furthest off an arc.
+    fn extends_piece(&self, motion: &[MotionSample], index: usize) -> bool {
+        let last = match self.trail.last() {
+            Some(last) if self.trail.len() >= 2 => last,
+            _ => return false,
+        };
+        let sample = &motion[index];
+        if sample.block != last.block {
+            return false;
+        }
+        let from = &motion[self.piece_start].position;
+        let end = &motion[index - 1].position;
+        let length = |point: &[f32; 4]| {
+            let offset = [point[0] - from[0], point[1] - from[1], point[2] - from[2]];
+            (offset[0] * offset[0] + offset[1] * offset[1] + offset[2] * offset[2]).sqrt()
+        };
+        if length(&sample.position) < length(end) {
+            return false;
+        }
+        let span = index - self.piece_start;
+        (1..4).all(|quarter| {
+            let passed = &motion[self.piece_start + span * quarter / 4].position;
+            distance_to_line(passed, from, &sample.position) <= self.tolerance
+        })
+    }
+
+    // Takes in the recorded motion up to `time`
+    pub fn follow(&mut self, motion: &[MotionSample], time: f32) {
+        while let Some(sample) = motion.get(self.followed) {
+            if sample.time > time as f64 {
+                break;
+            }
+            let point = TrailPoint {
+                x: sample.position[0],
+                y: sample.position[1],
+                z: sample.position[2],
+                block: sample.block,
+            };
+            if self.extends_piece(motion, self.followed) {
+                *self.trail.last_mut().unwrap() = point;
+            } else {
+                self.piece_start = self.followed.saturating_sub(1);
+                self.trail.push(point);
+            }
+            self.followed += 1;
+            self.tool.x = sample.position[0];
+            self.tool.y = sample.position[1];
+            self.tool.z = sample.position[2];
+            self.tool.e = sample.position[3];
+            self.tool.velocity = sample.velocity;
+        }
+    }
+
+    // Starts over from the recorded motion up to `time`, the tool back at the
+    // origin it starts from
+    pub fn replay(&mut self, motion: &[MotionSample], time: f32) {
+        self.trail.clear();
+        self.followed = 0;
+        self.piece_start = 0;
+        self.tool = simple_machine::ToolState::new();
+        self.follow(motion, time);
+    }
+
+    fn colour(&self, point: &TrailPoint) -> Colour {
+        let kind = match self.kinds.get(&point.block) {
+            Some(kind) => *kind,
+            None => simple_machine::MoveKind::Feed,
+        };
+        match (self.colouring, kind) {
+            (Colouring::MoveKind, kind)
+            | (Colouring::Depth, kind @ simple_machine::MoveKind::Rapid) => kind_colour(kind),
+            (Colouring::Depth, _) => {
+                let (top, bottom) = self.depth;
+                let t = if top > bottom {
+                    ((top - point.z) / (top - bottom)).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                lerp_colour(Colour::rgb(240, 200, 0), Colour::rgb(140, 0, 140), t)
+            }
+        }
+    }
+
+    fn line(lines: &mut Vec<Line>, from: (f32, f32), to: (f32, f32), colour: Colour) {
+        lines.push(Line { from, to, colour });
+    }
+
+    // Line with a head `size` pixels long at `to`
+    fn arrow(lines: &mut Vec<Line>, from: (f32, f32), to: (f32, f32), size: f32, colour: Colour) {
+        ToolTrail::line(lines, from, to, colour);
+        let angle = (from.1 - to.1).atan2(from.0 - to.0);
+        for side in [-0.4f32, 0.4].iter() {
+            let head = (
+                to.0 + size * (angle + side).cos(),
+                to.1 + size * (angle + side).sin(),
+            );
+            ToolTrail::line(lines, to, head, colour);
+        }
+    }
+
+    // Ring on the machine origin, X arrow in red, Y in green and Z in blue, a
+    // fixed size at any zoom. Arrows pointing at the camera are left out.
+    fn axes(&self, lines: &mut Vec<Line>, view: &View) {
+        let origin = view.screen_point(0.0, 0.0, 0.0);
+        let axes = [
+            ([1.0, 0.0, 0.0], Colour::rgb(200, 0, 0)),
+            ([0.0, 1.0, 0.0], Colour::rgb(0, 160, 0)),
+            ([0.0, 0.0, 1.0], Colour::rgb(0, 0, 200)),
+        ];
+        for (axis, colour) in axes.iter() {
+            let end = view.screen_point(axis[0], axis[1], axis[2]);
+            let length = 40.0 * self.ui_scale / view.scale;
+            let direction = ((end.0 - origin.0) * length, (end.1 - origin.1) * length);
+            if direction.0.hypot(direction.1) > 3.0 {
+                let to = (origin.0 + direction.0, origin.1 + direction.1);
+                ToolTrail::arrow(lines, origin, to, 8.0 * self.ui_scale, *colour);
+            }
+        }
+        let ring = |step: i32| {
+            let angle = step as f32 / 24.0 * 2.0 * std::f32::consts::PI;
+            let radius = 4.0 * self.ui_scale;
+            (
+                origin.0 + radius * angle.cos(),
+                origin.1 + radius * angle.sin(),
+            )
+        };
+        for step in 0..24 {
+            ToolTrail::line(lines, ring(step), ring(step + 1), Colour::BLACK);
+        }
+    }
+
+    // The tool at its true size and shape when it is in the tool table,
+    // otherwise a square outline around the tip and the tool axis up from it
+    fn tool_marker(&self, lines: &mut Vec<Line>, view: &View) {
+        let tool = &self.tool;
+        if let Some(shape) = self.tools.get(tool.tool) {
+            for [from, to] in shape.wireframe([tool.x, tool.y, tool.z]).iter() {
+                ToolTrail::line(
+                    lines,
+                    view.screen_point(from[0], from[1], from[2]),
+                    view.screen_point(to[0], to[1], to[2]),
+                    Colour::RED,
+                );
+            }
+            return;
+        }
+        let (x, y) = view.screen_point(tool.x, tool.y, tool.z);
+        let shank = view.screen_point(tool.x, tool.y, tool.z + 10.0);
+        ToolTrail::line(lines, (x, y), shank, Colour::RED);
+        let half = 3.0 * self.ui_scale;
+        let corners = [
+            (-half, -half),
+            (half, -half),
+            (half, half),
+            (-half, half),
+            (-half, -half),
+        ];
+        for corner in corners.windows(2) {
+            ToolTrail::line(
+                lines,
+                (x + corner[0].0, y + corner[0].1),
+                (x + corner[1].0, y + corner[1].1),
+                Colour::RED,
+            );
+        }
+    }
+
+    // Lines in image pixels, each piece of the trail coloured by where it ends
+    pub fn lines(&self, view: &View) -> Vec<Line> {
+        let mut lines = vec![];
+        self.axes(&mut lines, view);
+        for pair in self.trail.windows(2) {
+            ToolTrail::line(
+                &mut lines,
+                view.screen_point(pair[0].x, pair[0].y, pair[0].z),
+                view.screen_point(pair[1].x, pair[1].y, pair[1].z),
+                self.colour(&pair[1]),
+            );
+        }
+        for segment in self.toolpath.iter() {
+            if Some(segment.block) != self.highlight {
+                continue;
+            }
+            for pair in segment.points.windows(2) {
+                ToolTrail::line(
+                    &mut lines,
+                    view.screen_point(pair[0][0], pair[0][1], pair[0][2]),
+                    view.screen_point(pair[1][0], pair[1][1], pair[1][2]),
+                    Colour::MAGENTA,
+                );
+            }
+        }
+        self.tool_marker(&mut lines, view);
+        lines
+    }
+}
+
+// RGB image drawn in software, renders without a display or graphics driver
+#[derive(Debug, PartialEq, Clone)]
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32, background: Colour) -> Self {
+        let mut pixels = Vec::with_capacity(3 * (width * height) as usize);
+        for _ in 0..width * height {
+            pixels.extend_from_slice(&[background.r, background.g, background.b]);
+        }
+        Canvas {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Colour {
+        let index = 3 * (y * self.width + x) as usize;
+        Colour::rgb(
+            self.pixels[index],
+            self.pixels[index + 1],
+            self.pixels[index + 2],
+        )
+    }
+
+    fn plot(&mut self, x: i32, y: i32, colour: Colour) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        let index = 3 * (y as u32 * self.width + x as u32) as usize;
+        self.pixels[index..index + 3].copy_from_slice(&[colour.r, colour.g, colour.b]);
+    }
+
+    // One pixel wide like the window draws it, clipped to the image first so
+    // lines far off it cost nothing
+    pub fn line(&mut self, line: &Line) {
+        let (x0, y0) = line.from;
+        let (dx, dy) = (line.to.0 - x0, line.to.1 - y0);
+        let (mut start, mut end) = (0.0f32, 1.0f32);
+        let edges = [
+            (-dx, x0 + 1.0),
+            (dx, self.width as f32 - x0),
+            (-dy, y0 + 1.0),
+            (dy, self.height as f32 - y0),
+        ];
+        for (p, q) in edges.iter() {
+            if *p == 0.0 {
+                if *q < 0.0 {
+                    return;
+                }
+            } else if *p < 0.0 {
+                start = start.max(q / p);
+            } else {
+                end = end.min(q / p);
+            }
+        }
+        if start > end || !start.is_finite() || !end.is_finite() {
+            return;
+        }
+
+        let from = (x0 + start * dx, y0 + start * dy);
+        let to = (x0 + end * dx, y0 + end * dy);
+        let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil() as i32;
+        for step in 0..=steps {
+            let t = if steps > 0 {
+                step as f32 / steps as f32
+            } else {
+                0.0
+            };
+            self.plot(
+                (from.0 + t * (to.0 - from.0)).round() as i32,
+                (from.1 + t * (to.1 - from.1)).round() as i32,
+                line.colour,
+            );
+        }
+    }
+
+    pub fn draw(&mut self, lines: &[Line]) {
+        for line in lines.iter() {
+            self.line(line);
+        }
+    }
+
+    // 8 bit RGB PNG
+    pub fn write_png(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = vec![];
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // Bit depth, RGB, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        png_chunk(out, b"IHDR", &header)?;
+
+        let row = 3 * self.width as usize;
+        let mut scanlines = Vec::with_capacity((row + 1) * self.height as usize);
+        for line in self.pixels.chunks(row.max(1)) {
+            // No filter
+            scanlines.push(0);
+            scanlines.extend_from_slice(line);
+        }
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&scanlines)?;
+        png_chunk(out, b"IDAT", &encoder.finish()?)?;
+        png_chunk(out, b"IEND", &[])
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn png_chunk(out: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut checked = kind.to_vec();
+    checked.extend_from_slice(data);
+    out.write_all(&checked)?;
+    out.write_all(&crc32(&checked).to_be_bytes())
+}
+
+// The executed program as the window shows it once the run is over
+pub fn render_run(machine: &SimpleMachine, view: &View, width: u32, height: u32) -> Canvas {
+    let mut trail = ToolTrail::new(machine);
+    trail.set_tool(machine.toolstate());
+    trail.follow(machine.history().motion(), f32::INFINITY);
+    let mut canvas = Canvas::new(width, height, Colour::WHITE);
+    canvas.draw(&trail.lines(view));
+    canvas
+}
+
+// The run every `interval` seconds of machine time from the start to the end,
+// the trail so far and the tool where it was then, returns the number of frames
+pub fn render_frames(
+    machine: &SimpleMachine,
+    view: &View,
+    width: u32,
+    height: u32,
+    interval: f32,
+    frame: &mut dyn FnMut(&Canvas) -> io::Result<()>,
+) -> io::Result<usize> {
+    if interval.is_nan() || interval <= 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame interval must be positive",
+        ));
+    }
+    let history = machine.history();
+    let end = history.end_time();
+    let mut trail = ToolTrail::new(machine);
+    let mut frames = 0;
+    loop {
+        let time = (frames as f32 * interval).min(end);
+        match history.find_time(time) {
+            Some(index) if time < end => trail.set_tool(&history.snapshots()[index].state),
+            _ => trail.set_tool(machine.toolstate()),
+        }
+        trail.follow(history.motion(), time);
+        let mut canvas = Canvas::new(width, height, Colour::WHITE);
+        canvas.draw(&trail.lines(view));
+        frame(&canvas)?;
+        frames += 1;
+        if time >= end {
+            return Ok(frames);
+        }
+    }
+}
+
+// Animated GIF written a frame at a time, looping forever. Every frame only
+// holds the rectangle that changed since the one before, in at most 256 colours.
+pub struct GifWriter<'a> {
+    out: &'a mut dyn Write,
+    previous: Option<Canvas>,
+}
+
+impl<'a> GifWriter<'a> {
+    pub fn new(out: &'a mut dyn Write, width: u32, height: u32) -> io::Result<Self> {
+        if width == 0 || height == 0 || width > 0xffff || height > 0xffff {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "GIF images are 1 to 65535 pixels on a side",
+            ));
+        }
+        out.write_all(b"GIF89a")?;
+        out.write_all(&(width as u16).to_le_bytes())?;
+        out.write_all(&(height as u16).to_le_bytes())?;
+        // No global colour table, background and aspect ratio unused
+        out.write_all(&[0, 0, 0])?;
+        // Repeat forever
+        out.write_all(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+        Ok(GifWriter {
+            out,
+            previous: None,
+        })
+    }
+
+    // Shows `canvas` for `delay` hundredths of a second
+    pub fn frame(&mut self, canvas: &Canvas, delay: u16) -> io::Result<()> {
+        let (left, top, right, bottom) = match &self.previous {
+            Some(previous) => changed_rectangle(previous, canvas),
+            None => (0, 0, canvas.width, canvas.height),
+        };
+        let mut colours = Vec::with_capacity(((right - left) * (bottom - top)) as usize);
+        for y in top..bottom {
+            for x in left..right {
+                colours.push(canvas.pixel(x, y));
+            }
+        }
+        let (palette, indices) = palette(&colours);
+        let bits = (1..=8).find(|bits| palette.len() <= 1 << bits).unwrap_or(8);
+
+        // Graphic control, leaving the frame in place for the next one
+        self.out.write_all(&[0x21, 0xf9, 4, 1 << 2])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0, 0])?;
+
+        self.out.write_all(&[0x2c])?;
+        for value in [left, top, right - left, bottom - top].iter() {
+            self.out.write_all(&(*value as u16).to_le_bytes())?;
+        }
+        // Local colour table
+        self.out.write_all(&[0x80 | (bits as u8 - 1)])?;
+        for index in 0..1 << bits {
+            let colour = palette.get(index).cloned().unwrap_or(Colour::BLACK);
+            self.out.write_all(&[colour.r, colour.g, colour.b])?;
+        }
+
+        let code_size = bits.max(2);
+        self.out.write_all(&[code_size as u8])?;
+        for block in lzw_compress(&indices, code_size).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])?;
+        self.previous = Some(canvas.clone());
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.out.write_all(&[0x3b])?;
+        self.out.flush()
+    }
+}
+
+// Left, top, right and bottom of what differs, a single pixel when nothing does
+// as every frame needs an image
+fn changed_rectangle(previous: &Canvas, canvas: &Canvas) -> (u32, u32, u32, u32) {
+    let mut rectangle: Option<(u32, u32, u32, u32)> = None;
+    for y in 0..canvas.height {
+        for x in 0..canvas.width {
+            if previous.pixel(x, y) != canvas.pixel(x, y) {
+                rectangle = Some(match rectangle {
+                    Some((left, top, right, bottom)) => {
+                        (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1))
+                    }
+                    None => (x, y, x + 1, y + 1),
+                });
+            }
+        }
+    }
+    rectangle.unwrap_or((0, 0, 1, 1))
+}
+
+// Colours of the pixels and the index of each. When there are more than 256
+// the low bits of every channel are dropped until there are not.
+fn palette(colours: &[Colour]) -> (Vec<Colour>, Vec<u8>) {
+    let mut dropped = 0;
+    loop {
+        let quantise = |colour: &Colour| {
+            let channel = |value: u8| {
+                if dropped == 0 {
+                    value
+                } else {
+                    (value >> dropped << dropped) | (1 << (dropped - 1))
+                }
+            };
+            Colour::rgb(channel(colour.r), channel(colour.g), channel(colour.b))
+        };
+        let mut palette = vec![];
+        let mut lookup = HashMap::new();
+        let mut indices = Vec::with_capacity(colours.len());
+        for colour in colours.iter().map(quantise) {
+            let index = *lookup.entry(colour).or_insert_with(|| {
+                palette.push(colour);
+                palette.len() - 1
+            });
+            if palette.len() > 256 {
+                break;
+            }
+            indices.push(index as u8);
+        }
+        if palette.len() <= 256 {
+            return (palette, indices);
+        }
+        dropped += 1;
+    }
+}
+
+// Variable length LZW as GIF uses it, codes packed from the least significant bit
+fn lzw_compress(indices: &[u8], code_size: u32) -> Vec<u8> {
+    let clear = 1u16 << code_size;
+    let end = clear + 1;
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = code_size + 1;
+    let mut bytes = vec![];
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    let mut write = |code: u16, size: u32| {
+        buffer |= (code as u32) << bits;
+        bits += size;
+        while bits >= 8 {
+            bytes.push(buffer as u8);
+            buffer >>= 8;
+            bits -= 8;
+        }
+    };
+
+    write(clear, size);
+    let mut remaining = indices.iter();
+    if let Some(first) = remaining.next() {
+        let mut prefix = *first as u16;
+        for index in remaining {
+            if let Some(code) = codes.get(&(prefix, *index)) {
+                prefix = *code;
+                continue;
+            }
+            write(prefix, size);
+            if next >= 1 << size && size < 12 {
+                size += 1;
+            }
+            if next < 4096 {
+                codes.insert((prefix, *index), next);
+                next += 1;
+            } else {
+                write(clear, size);
+                codes.clear();
+                next = end + 1;
+                size = code_size + 1;
+            }
+            prefix = *index as u16;
+        }
+        write(prefix, size);
+        if next >= 1 << size && size < 12 {
+            size += 1;
+        }
+    }
+    write(end, size);
+    if bits > 0 {
+        bytes.push(buffer as u8);
+    }
+    bytes
+}
+
+fn kind_name(kind: simple_machine::MoveKind) -> &'static str {
+    match kind {
+        simple_machine::MoveKind::Rapid => "rapid",
+        simple_machine::MoveKind::Feed => "feed",
+        simple_machine::MoveKind::Arc => "arc",
+        simple_machine::MoveKind::Plunge => "plunge",
+        simple_machine::MoveKind::Retract => "retract",
+    }
+}
+
+// The program's toolpath through `view` as SVG, a layer per tool and kind of
+// move in the colours of the window. Tools are those of the executed run.
+pub fn write_svg(
+    machine: &SimpleMachine,
+    view: &View,
+    width: u32,
+    height: u32,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut tools = HashMap::new();
+    for snapshot in machine.history().snapshots() {
+        tools.entry(snapshot.block).or_insert(snapshot.state.tool);
+    }
+    let mut layers: BTreeMap<(i32, &str), Vec<&simple_machine::PathSegment>> = BTreeMap::new();
+    let toolpath = machine.toolpath();
+    for segment in toolpath.iter() {
+        let tool = tools.get(&segment.block).cloned().unwrap_or(0);
+        layers
+            .entry((tool, kind_name(segment.kind)))
+            .or_default()
+            .push(segment);
+    }
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+        width, height
+    )?;
+    writeln!(out, r#"<rect width="100%" height="100%" fill="white"/>"#)?;
+    for ((tool, kind), segments) in layers.iter() {
+        let colour = kind_colour(segments[0].kind);
+        writeln!(
+            out,
+            r#"<g id="T{0}-{1}" inkscape:groupmode="layer" inkscape:label="T{0} {1}" fill="none" stroke="rgb({2},{3},{4})">"#,
+            tool, kind, colour.r, colour.g, colour.b
+        )?;
+        for segment in segments.iter() {
+            let points: Vec<String> = segment
+                .points
+                .iter()
+                .map(|point| {
+                    let (x, y) = view.screen_point(point[0], point[1], point[2]);
+                    format!("{:.2},{:.2}", x, y)
+                })
+                .collect();
+            writeln!(
+                out,
+                r#"<polyline data-block="{}" points="{}"/>"#,
+                segment.block,
+                points.join(" ")
+            )?;
+        }
+        writeln!(out, "</g>")?;
+    }
+    writeln!(out, "</svg>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_machine::{MoveKind, ToolConfig};
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn machine(program: &str) -> SimpleMachine {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str(program);
+        machine
+    }
+
+    #[test]
+    fn test_trail_colours() {
+        let mut machine = machine("G0 X10 Z2\nG1 Z-2 F600\nG1 X20\nG0 Z2\n");
+        machine.run_to_end();
+        let mut trail = ToolTrail::new(&machine);
+        let motion = machine.history().motion();
+        trail.follow(motion, machine.toolstate().time);
+        // A piece per straight move
+        let blocks: Vec<i32> = trail.trail.iter().map(|point| point.block).collect();
+        assert_eq!(vec![0, 0, 1, 2, 3], blocks);
+
+        let colour = |trail: &ToolTrail, block| {
+            let point = trail
+                .trail
+                .iter()
+                .find(|point| point.block == block)
+                .unwrap();
+            trail.colour(point)
+        };
+        assert_eq!(kind_colour(MoveKind::Rapid), colour(&trail, 0));
+        assert_eq!(kind_colour(MoveKind::Plunge), colour(&trail, 1));
+        assert_eq!(kind_colour(MoveKind::Feed), colour(&trail, 2));
+        assert_eq!(kind_colour(MoveKind::Retract), colour(&trail, 3));
+
+        // By depth the cut at the bottom is purple, rapids stay grey
+        trail.toggle_colouring();
+        assert_eq!(Colour::rgb(140, 0, 140), colour(&trail, 2));
+        assert_eq!(kind_colour(MoveKind::Rapid), colour(&trail, 0));
+
+        // A line per piece plus the X and Y axes, origin ring and tool, Z points
+        // at the camera from the top
+        let view = View::new(800, 600);
+        let lines = trail.trail.len() - 1 + 6 + 24 + 5;
+        assert_eq!(lines, trail.lines(&view).len());
+
+        trail.replay(machine.history().motion(), 0.0);
+        assert!(trail.trail.is_empty());
+    }
+
+    #[test]
+    fn test_arc_pieces() {
+        // Chords of the half circle stay within the tolerance of it
+        let mut machine = machine("G2 X20 Y0 I10 J0 F600\n");
+        machine.run_to_end();
+        let mut trail = ToolTrail::new(&machine);
+        let motion = machine.history().motion();
+        trail.follow(motion, machine.toolstate().time / 2.0);
+        let followed = trail.trail.len();
+        trail.follow(motion, machine.toolstate().time);
+        assert!(trail.trail.len() > followed);
+        assert!(trail.trail.len() > 20 && trail.trail.len() < motion.len() / 10);
+        for pair in trail.trail.windows(2) {
+            let middle = (0.5 * (pair[0].x + pair[1].x), 0.5 * (pair[0].y + pair[1].y));
+            let radius = (middle.0 - 10.0).hypot(middle.1);
+            assert!(radius > 10.0 - 2.0 * trail.tolerance && radius < 10.0 + trail.tolerance);
+        }
+    }
+
+    #[test]
+    fn test_markers() {
+        // The square marker doubles on a 4K display, the program does not
+        let mut machine = machine("T1 M6\nG0 X10\n");
+        let mut trail = ToolTrail::new(&machine);
+        let view = View::new(800, 600);
+        let corner = |trail: &ToolTrail| {
+            let lines = trail.lines(&view);
+            lines[lines.len() - 4].from
+        };
+        assert_eq!((397.0, 297.0), corner(&trail));
+        trail.set_ui_scale(2.0);
+        assert_eq!((394.0, 294.0), corner(&trail));
+
+        // A tool from the table at its size, 10 wide at 4 pixels per unit, its
+        // rings and meridians in place of the square
+        let mut toolconfig = ToolConfig::new();
+        toolconfig.tools = ToolTable::parse("T1 flat D10 L20").unwrap();
+        machine.set_toolconfig(toolconfig);
+        machine.run_to_end();
+        let mut trail = ToolTrail::new(&machine);
+        trail.set_tool(machine.toolstate());
+        let lines = trail.lines(&view);
+        let right = lines[lines.len() - (2 * 24 + 8 * 2)..]
+            .iter()
+            .map(|line| line.from.0)
+            .fold(f32::MIN, f32::max);
+        assert!((right - (400.0 + 4.0 * 15.0)).abs() < 1e-3);
+        trail.set_tools(&ToolTable::new());
+        assert_eq!(lines.len() - (64 - 5), trail.lines(&view).len());
+    }
+
+    #[test]
+    fn test_picking() {
+        let machine = machine("G0 X10\nG1 Y10 F600\nG1 X0\n");
+        let mut trail = ToolTrail::new(&machine);
+        assert_eq!(Some([10.0, 5.0, 0.0]), trail.block_center(1));
+        let view = View::new(800, 600);
+        let (x, y) = view.screen_point(10.0, 5.0, 0.0);
+        assert_eq!(Some(1), trail.pick(&view, x + 3.0, y, 6.0));
+        assert_eq!(None, trail.pick(&view, x + 10.0, y, 6.0));
+
+        // The picked move is drawn once more on top of the trail
+        let lines = trail.lines(&view).len();
+        trail.set_highlight(Some(1));
+        assert_eq!(lines + 1, trail.lines(&view).len());
+    }
+
+    #[test]
+    fn test_canvas_and_png() {
+        let mut canvas = Canvas::new(20, 10, Colour::WHITE);
+        canvas.line(&Line {
+            from: (-100.0, 5.0),
+            to: (100.0, 5.0),
+            colour: Colour::RED,
+        });
+        canvas.line(&Line {
+            from: (3.0, -1e9),
+            to: (3.0, 1e9),
+            colour: Colour::BLACK,
+        });
+        assert_eq!(Colour::RED, canvas.pixel(0, 5));
+        assert_eq!(Colour::RED, canvas.pixel(19, 5));
+        assert_eq!(Colour::BLACK, canvas.pixel(3, 0));
+        assert_eq!(Colour::WHITE, canvas.pixel(4, 4));
+
+        let mut png = vec![];
+        canvas.write_png(&mut png).unwrap();
+        assert_eq!(b"\x89PNG\r\n\x1a\n", &png[..8]);
+        assert_eq!(b"IHDR", &png[12..16]);
+        assert_eq!([0, 0, 0, 20, 0, 0, 0, 10], png[16..24]);
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+        // Rows of a filter byte and 20 pixels
+        let idat = 8 + 25;
+        let length =
+            u32::from_be_bytes([png[idat], png[idat + 1], png[idat + 2], png[idat + 3]]) as usize;
+        assert_eq!(b"IDAT", &png[idat + 4..idat + 8]);
+        let mut scanlines = vec![];
+        ZlibDecoder::new(&png[idat + 8..idat + 8 + length])
+            .read_to_end(&mut scanlines)
+            .unwrap();
+        assert_eq!(10 * 61, scanlines.len());
+        assert_eq!([0, 255, 0, 0], scanlines[5 * 61..5 * 61 + 4]);
+        assert_eq!(b"IEND", &png[png.len() - 8..png.len() - 4]);
+
+        // Deflated, not stored
+        let mut png = vec![];
+        Canvas::new(800, 600, Colour::WHITE)
+            .write_png(&mut png)
+            .unwrap();
+        assert!(png.len() < 10_000);
+    }
+
+    #[test]
+    fn test_render_and_svg() {
+        let mut machine = machine("G0 X10\nT2 M6\nG1 Y10 F600\nG2 X20 Y0 I0 J-10\n");
+        machine.run_to_end();
+        let view = View::new(100, 100);
+        let canvas = render_run(&machine, &view, 100, 100);
+        assert_eq!(kind_colour(MoveKind::Feed), canvas.pixel(90, 30));
+
+        let mut svg = vec![];
+        write_svg(&machine, &view, 100, 100, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.contains(r#"<g id="T0-rapid""#));
+        assert!(svg.contains(r#"inkscape:label="T2 feed""#));
+        assert!(svg.contains(r#"<polyline data-block="2" points="90.00,50.00 90.00,10.00"/>"#));
+        assert_eq!(3, svg.matches("<g ").count());
+        assert!(svg.ends_with("</svg>\n"));
+    }
+
+    #[test]
+    fn test_example_svg() {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine
+            .load_file(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/examples/holepattern.gcode"
+            ))
+            .unwrap();
+        machine.run_to_end();
+        let view = View::new(800, 600);
+        let mut svg = vec![];
+        write_svg(&machine, &view, 800, 600, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+
+        // The rapid to the first hole of each tool, in blocks that set modes too
+        assert!(svg.contains(r#"<g id="T0-rapid""#));
+        for block in [3, 15, 27].iter() {
+            assert!(svg.contains(&format!(r#"<polyline data-block="{}" "#, block)));
+        }
+    }
+
+    #[test]
+    fn test_blended_frames() {
+        // Rounded corners run as profiles of their own, the frames still follow
+        // the square side by side
+        let mut machine = machine("G64 P0.5\nG1 X10 F600\nG1 Y10\nG1 X0\nG1 Y0\n");
+        machine.run_to_end();
+        let view = View::new(100, 100);
+        let mut frames = vec![];
+        render_frames(&machine, &view, 100, 100, 0.1, &mut |canvas| {
+            frames.push(canvas.clone());
+            Ok(())
+        })
+        .unwrap();
+
+        let drawn = |x, y| {
+            frames
+                .iter()
+                .position(|frame| frame.pixel(x, y) == kind_colour(MoveKind::Feed))
+        };
+        assert!(drawn(90, 30).unwrap() < drawn(70, 10).unwrap());
+        assert_eq!(
+            &render_run(&machine, &view, 100, 100),
+            frames.last().unwrap()
+        );
+    }
+
+    // Frames of a GIF as an independent decoder puts them on the screen
+    fn decode_gif(bytes: &[u8]) -> Vec<Canvas> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(bytes).unwrap();
+        let (width, height) = (decoder.width() as u32, decoder.height() as u32);
+        let mut screen = Canvas::new(width, height, Colour::BLACK);
+        let mut frames = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            for (index, pixel) in frame.buffer.chunks(4).enumerate() {
+                let x = frame.left as u32 + index as u32 % frame.width as u32;
+                let y = frame.top as u32 + index as u32 / frame.width as u32;
+                screen.plot(
+                    x as i32,
+                    y as i32,
+                    Colour::rgb(pixel[0], pixel[1], pixel[2]),
+                );
+            }
+            frames.push(screen.clone());
+        }
+        frames
+    }
+
+    #[test]
+    fn test_frames_and_gif() {
+        let mut machine = machine("G1 X10 F600\nG1 Y10\n");
+        machine.run_to_end();
+        let view = View::new(100, 100);
+        let mut frames = vec![];
+        let count = render_frames(&machine, &view, 100, 100, 0.5, &mut |canvas| {
+            frames.push(canvas.clone());
+            Ok(())
+        })
+        .unwrap();
+
+        // Every half second and the end, which looks like the finished run
+        let end = machine.history().end_time();
+        assert_eq!((end / 0.5).ceil() as usize + 1, count);
+        assert_eq!(count, frames.len());
+        assert_eq!(Colour::WHITE, frames[2].pixel(90, 30));
+        assert_eq!(kind_colour(MoveKind::Feed), frames[count - 1].pixel(90, 30));
+        assert_eq!(
+            &render_run(&machine, &view, 100, 100),
+            frames.last().unwrap()
+        );
+        assert!(render_frames(&machine, &view, 100, 100, 0.0, &mut |_| Ok(())).is_err());
+
+        // Only what moved is in the next frame
+        let mut moved = frames[0].clone();
+        moved.line(&Line {
+            from: (10.0, 20.0),
+            to: (30.0, 25.0),
+            colour: Colour::RED,
+        });
+        assert_eq!((10, 20, 31, 26), changed_rectangle(&frames[0], &moved));
+        assert_eq!((0, 0, 1, 1), changed_rectangle(&moved, &moved));
+
+        let mut gif = vec![];
+        let mut writer = GifWriter::new(&mut gif, 100, 100).unwrap();
+        writer.frame(&frames[0], 10).unwrap();
+        writer.frame(&moved, 10).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(b"GIF89a", &gif[..6]);
+        assert_eq!([100, 0, 100, 0], gif[6..10]);
+        assert_eq!(Some(&0x3b), gif.last());
+        assert_eq!(vec![frames[0].clone(), moved], decode_gif(&gif));
+        assert!(GifWriter::new(&mut vec![], 70000, 10).is_err());
+
+        // Quantised past 256 colours, the table restarting when full
+        let gradient: Vec<Colour> = (0..1000)
+            .map(|value| Colour::rgb((value % 256) as u8, (value / 4) as u8, 0))
+            .collect();
+        let (colours, indices) = palette(&gradient);
+        assert!(colours.len() <= 256);
+        assert_eq!(gradient.len(), indices.len());
+
+        // Noise fills the code table, a flat image grows the codes to 12 bits
+        let mut noise = Canvas::new(150, 150, Colour::WHITE);
+        for index in 0..150 * 150u32 {
+            let value = (index.wrapping_mul(2_654_435_761) >> 28) as u8;
+            noise.plot(
+                (index % 150) as i32,
+                (index / 150) as i32,
+                Colour::rgb(value * 16, 0, 255 - value),
+            );
+        }
+        let flat = Canvas::new(150, 150, Colour::RED);
+        let mut gif = vec![];
+        let mut writer = GifWriter::new(&mut gif, 150, 150).unwrap();
+        writer.frame(&noise, 10).unwrap();
+        writer.frame(&flat, 10).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(vec![noise, flat], decode_gif(&gif));
+    }
+}
@@ -1,8 +1,20 @@
+use crate::breakpoints::{Breakpoint, Condition};
+use crate::estimate::CycleTimeEstimate;
+use crate::events::{MachineError, MachineSink};
 use crate::gcode;
+use crate::history::{History, ModalState, Snapshot};
+use crate::planner;
+use crate::planner::{AxisDynamics, JunctionMode, VelocityProfile};
+use crate::stepgen::{StepGenerator, StepRateMonitor};
+use crate::tools::ToolTable;
+use crate::vcd::VcdWriter;
+use std::cell::RefCell;
 use std::f32;
 use std::f32::consts::PI;
-use std::sync::mpsc;
-use std::thread;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::rc::Rc;
 
 #[derive(Debug, Clone, Copy)]
 struct FixedResolution {
@@ -10,43 +22,35 @@ struct FixedResolution {
     resolution: i32,
 }
 
+// Nearest multiple of 1 / `resolution`, in those units. Truncating would put
+// a target such as -67.64 a step short.
+fn to_fixed(value: f32, resolution: i32) -> i64 {
+    (value as f64 * resolution as f64).round() as i64
+}
+
 impl FixedResolution {
     pub fn new(value: f32, resolution: i32) -> Self {
         Self {
-            raw_value: (value * resolution as f32) as i64,
-            resolution: resolution,
+            raw_value: to_fixed(value, resolution),
+            resolution,
         }
     }
     pub fn repr(&self) -> f32 {
-        return self.raw_value as f32 / self.resolution as f32;
+        self.raw_value as f32 / self.resolution as f32
     }
 
     pub fn increment(&self, direction: i32) -> FixedResolution {
-        return FixedResolution {
+        FixedResolution {
             raw_value: self.raw_value + direction as i64,
             resolution: self.resolution,
-        };
-    }
-
-    pub fn add(&self, value: FixedResolution) -> FixedResolution {
-        return FixedResolution {
-            raw_value: self.raw_value + value.raw_value,
-            resolution: self.resolution,
-        };
+        }
     }
 
     pub fn subtract(&self, value: FixedResolution) -> FixedResolution {
-        return FixedResolution {
+        FixedResolution {
             raw_value: self.raw_value - value.raw_value,
             resolution: self.resolution,
-        };
-    }
-
-    pub fn multiply_raw(&self, value: f32) -> FixedResolution {
-        return FixedResolution {
-            raw_value: ((self.raw_value as f32) * value) as i64,
-            resolution: self.resolution,
-        };
+        }
     }
 
     pub fn equal(&self, comp: &FixedResolution) -> bool {
@@ -75,23 +79,51 @@ enum Command {
     StepperZ,
     StepperE,
     Feedrate,
+    Velocity,
+    Elapsed,
+    Tool,
     Done,
-    Quit,
+}
+
+impl Command {
+    fn axis(&self) -> Option<usize> {
+        match self {
+            Command::StepperX => Some(0),
+            Command::StepperY => Some(1),
+            Command::StepperZ => Some(2),
+            Command::StepperE => Some(3),
+            _ => None,
+        }
+    }
+
+    fn stepper(axis: usize) -> Command {
+        match axis {
+            0 => Command::StepperX,
+            1 => Command::StepperY,
+            2 => Command::StepperZ,
+            _ => Command::StepperE,
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct CommandEntry {
+struct CommandEntry {
     command: Command,
     value: f32,
+    // When a step happens, seconds since program start
+    time: f64,
 }
 
 #[derive(Debug, Clone)]
-pub struct SyncEntry {
+struct SyncEntry {
     steps_x: i32,
     steps_y: i32,
     steps_z: i32,
     steps_e: i32,
     rate: f32,
+    velocity: f32,
+    elapsed: f32,
+    tool: i32,
 }
 
 impl SyncEntry {
@@ -101,104 +133,84 @@ impl SyncEntry {
             steps_y: 0,
             steps_z: 0,
             steps_e: 0,
-            rate: 0.0,
+            rate: ToolState::new().feedrate,
+            velocity: 0.0,
+            elapsed: 0.0,
+            tool: 0,
         }
     }
 }
 
-pub fn start_machine(
-    filepath: String,
-    toolstate: mpsc::Sender<SyncEntry>,
-    config_sync: mpsc::Sender<ToolConfig>,
-) -> Vec<thread::JoinHandle<()>> {
-    let (tx, rx) = mpsc::channel::<CommandEntry>();
-    let (sync_tx, sync_rx) = mpsc::channel::<SyncEntry>();
-
-    let machine_thread_handle = thread::spawn(move || {
-        let mut machine = SimpleMachine::new(filepath, tx.clone(), sync_rx, config_sync.clone());
+// The stepper stage, counts the steps and time the machine emits per move
+// and checks their timing
+struct Stepper {
+    syncentry: SyncEntry,
+    step_rates: StepRateMonitor,
+}
 
-        let mut return_code = 0;
-        while return_code == 0 {
-            return_code = machine.process();
+impl Stepper {
+    fn new(toolconfig: &ToolConfig) -> Self {
+        Stepper {
+            syncentry: SyncEntry::new(),
+            step_rates: StepRateMonitor::new(toolconfig.max_step_frequency()),
         }
+    }
 
-        tx.send(CommandEntry {
-            command: Command::Quit,
-            value: 0.0,
-        })
-        .expect("Sent failed!");
-    });
-
-    let stepper_thread_handle = thread::spawn(move || {
-        let mut syncentry = SyncEntry::new();
-        let mut gui_syncentry = SyncEntry::new();
-        let mut return_code = 0;
-        let mut counter = 0;
-        while return_code == 0 {
-            match rx.recv() {
-                Ok(entry) => {
-                    let sigmoid_value = if entry.value < 0.0 {
-                        -1
-                    } else if entry.value > 0.0 {
-                        1
-                    } else {
-                        0
-                    };
-
-                    match &entry.command {
-                        Command::StepperX => {
-                            syncentry.steps_x += sigmoid_value;
-                            gui_syncentry.steps_x += sigmoid_value;
-                        }
-                        Command::StepperY => {
-                            syncentry.steps_y += sigmoid_value;
-                            gui_syncentry.steps_y += sigmoid_value;
-                        }
-                        Command::StepperZ => {
-                            syncentry.steps_z += sigmoid_value;
-                            gui_syncentry.steps_z += sigmoid_value;
-                        }
-                        Command::StepperE => {
-                            syncentry.steps_e += sigmoid_value;
-                            gui_syncentry.steps_e += sigmoid_value;
-                        }
-                        Command::Feedrate => {
-                            syncentry.rate = entry.value;
-                            gui_syncentry.rate = entry.value;
-                        }
-                        Command::Done => {
-                            sync_tx.send(syncentry.clone()).expect("Sent failed!");
-
-                            // Reset the counters
-                            syncentry.steps_x = 0;
-                            syncentry.steps_y = 0;
-                            syncentry.steps_z = 0;
-                            syncentry.steps_e = 0;
-                        }
-                        Command::Quit => {
-                            return_code = 1;
-                        }
-                    };
-
-                    toolstate.send(gui_syncentry.clone()).expect("Sent failed!");
-
-                    // Reset the counters
-                    gui_syncentry.steps_x = 0;
-                    gui_syncentry.steps_y = 0;
-                    gui_syncentry.steps_z = 0;
-                    gui_syncentry.steps_e = 0;
+    // Returns the steps and time of the move once it is done
+    fn handle(&mut self, entry: &CommandEntry) -> Option<SyncEntry> {
+        let sigmoid_value = if entry.value < 0.0 {
+            -1
+        } else if entry.value > 0.0 {
+            1
+        } else {
+            0
+        };
 
-                    counter += 1;
-                }
-                Err(something) => {
-                    println!("Unable to fetch work item: {:?}", something);
-                    return_code = 1;
-                }
-            }
+        if let Some(axis) = entry.command.axis() {
+            self.step_rates.step(axis, entry.time);
         }
-    });
 
-    return vec![machine_thread_handle, stepper_thread_handle];
+        let syncentry = &mut self.syncentry;
+        match &entry.command {
+            Command::StepperX => {
+                syncentry.steps_x += sigmoid_value;
+            }
+            Command::StepperY => {
+                syncentry.steps_y += sigmoid_value;
+            }
+            Command::StepperZ => {
+                syncentry.steps_z += sigmoid_value;
+            }
+            Command::StepperE => {
+                syncentry.steps_e += sigmoid_value;
+            }
+            Command::Feedrate => {
+                syncentry.rate = entry.value;
+            }
+            Command::Velocity => {
+                syncentry.velocity = entry.value;
+            }
+            Command::Elapsed => {
+                syncentry.elapsed += entry.value;
+            }
+            Command::Tool => {
+                syncentry.tool = entry.value as i32;
+            }
+            Command::Done => {
+                let done = syncentry.clone();
+
+                // Reset the counters
+                syncentry.steps_x = 0;
+                syncentry.steps_y = 0;
+                syncentry.steps_z = 0;
+                syncentry.steps_e = 0;
+                syncentry.elapsed = 0.0;
+                self.step_rates.end_move();
+                return Some(done);
+            }
+        };
+        None
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -208,6 +220,11 @@ pub struct ToolState {
     pub z: f32,
     pub e: f32,
     pub feedrate: f32,
+    // Planned path velocity, units/min
+    pub velocity: f32,
+    // Simulated time since program start, seconds
+    pub time: f32,
+    pub tool: i32,
 }
 impl ToolState {
     pub fn new() -> Self {
@@ -217,16 +234,107 @@ impl ToolState {
             z: 0.0,
             e: 0.0,
             feedrate: 1000.0,
+            velocity: 0.0,
+            time: 0.0,
+            tool: 0,
+        }
+    }
+}
+
+impl Default for ToolState {
+    fn default() -> Self {
+        ToolState::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TravelLimits {
+    pub min: f32,
+    pub max: f32,
+}
+impl TravelLimits {
+    pub fn new(min: f32, max: f32) -> Self {
+        TravelLimits { min, max }
+    }
+
+    // Anywhere the program goes
+    pub fn unlimited() -> Self {
+        TravelLimits::new(f32::NEG_INFINITY, f32::INFINITY)
+    }
+
+    // "-150:150", the minimum first
+    pub fn parse(text: &str) -> Option<TravelLimits> {
+        let (min, max) = text.split_once(':')?;
+        let limits = TravelLimits::new(min.parse().ok()?, max.parse().ok()?);
+        if limits.min > limits.max {
+            return None;
+        }
+        Some(limits)
+    }
+
+    // Signed distance outside the travel, negative when below the minimum
+    pub fn overshoot(&self, position: f32) -> Option<f32> {
+        if position < self.min {
+            Some(position - self.min)
+        } else if position > self.max {
+            Some(position - self.max)
+        } else {
+            None
         }
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct SoftLimitViolation {
+    pub block: i32,
+    pub axis: char,
+    pub position: f32,
+    pub overshoot: f32,
+}
+
+impl fmt::Display for SoftLimitViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "N{}: {} axis at {:.3} exceeds travel by {:.3}",
+            self.block, self.axis, self.position, self.overshoot
+        )
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct ToolConfig {
     pub steps_per_unit_x: i32,
     pub steps_per_unit_y: i32,
     pub steps_per_unit_z: i32,
     pub steps_per_unit_e: i32,
+    // Unlimited unless configured, a move past them is reported and only
+    // stops the program when halting is asked for
+    pub travel_x: TravelLimits,
+    pub travel_y: TravelLimits,
+    pub travel_z: TravelLimits,
+    pub halt_on_soft_limit: bool,
+    // Velocity, acceleration and cornering velocity jump per axis. Moves are
+    // trapezoidal, there is no jerk limit.
+    pub dynamics_x: AxisDynamics,
+    pub dynamics_y: AxisDynamics,
+    pub dynamics_z: AxisDynamics,
+    pub dynamics_e: AxisDynamics,
+    pub junction: JunctionMode,
+    // Number of upcoming moves planned ahead of the executing one
+    pub lookahead: usize,
+    // Seconds spent on an M6 tool change
+    pub tool_change_time: f32,
+    // Highest step pulse rate the drivers accept, Hz
+    pub max_step_frequency_x: f32,
+    pub max_step_frequency_y: f32,
+    pub max_step_frequency_z: f32,
+    pub max_step_frequency_e: f32,
+    // Step pulse timing written to the signal dump, seconds
+    pub step_pulse_width: f32,
+    pub dir_setup_time: f32,
+    // Shapes of the tools T selects, for drawing them
+    pub tools: ToolTable,
 }
 impl ToolConfig {
     pub fn new() -> Self {
@@ -235,10 +343,114 @@ impl ToolConfig {
             steps_per_unit_y: 100,
             steps_per_unit_z: 100,
             steps_per_unit_e: 100,
+            travel_x: TravelLimits::unlimited(),
+            travel_y: TravelLimits::unlimited(),
+            travel_z: TravelLimits::unlimited(),
+            halt_on_soft_limit: false,
+            dynamics_x: AxisDynamics::new(100.0, 500.0, 10.0),
+            dynamics_y: AxisDynamics::new(100.0, 500.0, 10.0),
+            dynamics_z: AxisDynamics::new(20.0, 100.0, 0.4),
+            dynamics_e: AxisDynamics::new(50.0, 1000.0, 5.0),
+            junction: JunctionMode::Deviation(0.01),
+            lookahead: 16,
+            tool_change_time: 5.0,
+            max_step_frequency_x: 40000.0,
+            max_step_frequency_y: 40000.0,
+            max_step_frequency_z: 40000.0,
+            max_step_frequency_e: 40000.0,
+            step_pulse_width: 2e-6,
+            dir_setup_time: 1e-6,
+            tools: ToolTable::new(),
+        }
+    }
+
+    pub fn max_step_frequency(&self) -> [f32; 4] {
+        [
+            self.max_step_frequency_x,
+            self.max_step_frequency_y,
+            self.max_step_frequency_z,
+            self.max_step_frequency_e,
+        ]
+    }
+
+    pub fn steps_per_unit(&self) -> [i32; 4] {
+        [
+            self.steps_per_unit_x,
+            self.steps_per_unit_y,
+            self.steps_per_unit_z,
+            self.steps_per_unit_e,
+        ]
+    }
+
+    // Longest step of the X, Y and Z axes, units
+    pub fn step_size(&self) -> f32 {
+        let steps_per_unit = self
+            .steps_per_unit_x
+            .min(self.steps_per_unit_y)
+            .min(self.steps_per_unit_z)
+            .max(1);
+        1.0 / steps_per_unit as f32
+    }
+
+    pub fn dynamics(&self) -> [AxisDynamics; 4] {
+        [
+            self.dynamics_x,
+            self.dynamics_y,
+            self.dynamics_z,
+            self.dynamics_e,
+        ]
+    }
+
+    fn travel(&self, axis: char) -> Option<TravelLimits> {
+        match axis {
+            'X' => Some(self.travel_x),
+            'Y' => Some(self.travel_y),
+            'Z' => Some(self.travel_z),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ToolConfig {
+    fn default() -> Self {
+        ToolConfig::new()
+    }
+}
+
+// Operator overrides in percent of the programmed feed, the rapid traverse
+// velocity and the programmed spindle speed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Overrides {
+    pub feed: f32,
+    pub rapid: f32,
+    pub spindle: f32,
+}
+
+impl Overrides {
+    pub fn new() -> Self {
+        Overrides {
+            feed: 100.0,
+            rapid: 100.0,
+            spindle: 100.0,
+        }
+    }
+
+    // Within the range of the usual control panel switches
+    pub fn clamped(&self) -> Self {
+        Overrides {
+            feed: self.feed.clamp(10.0, 200.0),
+            rapid: self.rapid.clamp(5.0, 100.0),
+            spindle: self.spindle.clamp(50.0, 150.0),
         }
     }
 }
 
+impl Default for Overrides {
+    fn default() -> Self {
+        Overrides::new()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 struct Position {
     x: f32,
@@ -258,355 +470,1763 @@ impl Position {
 fn calculate_angles(start_pos: Position, end_pos: Position, center: Position) -> (f32, f32, f32) {
     let radius = (center.x * center.x + center.y * center.y).sqrt();
 
-    let start_angle = (-1.0 * center.y).atan2(-1.0 * center.x);
+    let start_angle = (-center.y).atan2(-center.x);
     let stop_angle =
         (end_pos.y - (start_pos.y + center.y)).atan2(end_pos.x - (start_pos.x + center.x));
 
-    return (radius, start_angle, stop_angle);
+    (radius, start_angle, stop_angle)
 }
 
-fn calculate_units(start_angle: f32, stop_angle: f32) -> (Position, Position) {
-    let start_unit = Position::new(start_angle.cos(), start_angle.sin());
-    let stop_unit = Position::new(stop_angle.cos(), stop_angle.sin());
-    return (start_unit, stop_unit);
+// Bounding box of an arc, the quadrant points crossed by the sweep included
+fn arc_extents(
+    center: (f32, f32),
+    radius: f32,
+    start_angle: f32,
+    stop_angle: f32,
+) -> ((f32, f32), (f32, f32)) {
+    let point = |angle: f32| {
+        (
+            center.0 + radius * angle.cos(),
+            center.1 + radius * angle.sin(),
+        )
+    };
+    let (low, high) = if start_angle < stop_angle {
+        (start_angle, stop_angle)
+    } else {
+        (stop_angle, start_angle)
+    };
+
+    let start = point(start_angle);
+    let mut min = start;
+    let mut max = start;
+    let mut extend = |(x, y): (f32, f32)| {
+        min = (min.0.min(x), min.1.min(y));
+        max = (max.0.max(x), max.1.max(y));
+    };
+    extend(point(stop_angle));
+    for quadrant in -4..=8 {
+        let angle = quadrant as f32 * 0.5 * PI;
+        if angle > low && angle < high {
+            extend(point(angle));
+        }
+    }
+    (min, max)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Radius and start/stop angles of an arc, the stop angle unwrapped in the
+// direction of travel
+fn arc_sweep(
+    from: &ToolState,
+    target: &ToolState,
+    center: (f32, f32),
+    clockwise: bool,
+) -> (f32, f32, f32) {
+    let (radius, start_angle, raw_stop_angle) = calculate_angles(
+        Position::new(from.x, from.y),
+        Position::new(target.x, target.y),
+        Position::new(center.0, center.1),
+    );
+    let stop_angle = if start_angle > raw_stop_angle {
+        if clockwise {
+            raw_stop_angle
+        } else {
+            2.0 * PI + raw_stop_angle
+        }
+    } else if clockwise {
+        raw_stop_angle - 2.0 * PI
+    } else {
+        raw_stop_angle
+    };
+    (radius, start_angle, stop_angle)
+}
 
-    #[test]
-    fn test_calculate_units() {
-        {
-            let (_, start_angle, stop_angle) = calculate_angles(
-                Position { x: 10.0, y: 10.0 },
-                Position { x: 20.0, y: 20.0 },
-                Position { x: 0.0, y: 10.0 },
-            );
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Motion {
+    Linear { rapid: bool },
+    Arc { clockwise: bool, center: (f32, f32) },
+}
 
-            let result = calculate_units(start_angle, stop_angle);
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PathMode {
+    // G61, every move comes to a stop at its end point
+    ExactStop,
+    // G61.1, corners are passed exactly, at the planned junction velocity
+    ExactPath,
+    // G64, corners are rounded off within `tolerance` (P) and collinear moves
+    // within `naive_cam` (Q) of a straight line are merged
+    Blending {
+        tolerance: Option<f32>,
+        naive_cam: Option<f32>,
+    },
+}
 
-            assert_eq!(Position { x: 0.0, y: -1.0 }, result.0);
-            assert_eq!(Position { x: 1.0, y: 0.0 }, result.1);
+impl fmt::Display for PathMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathMode::ExactStop => write!(f, "G61"),
+            PathMode::ExactPath => write!(f, "G61.1"),
+            PathMode::Blending {
+                tolerance,
+                naive_cam,
+            } => {
+                write!(f, "G64")?;
+                if let Some(tolerance) = tolerance {
+                    write!(f, " P{}", tolerance)?;
+                }
+                if let Some(naive_cam) = naive_cam {
+                    write!(f, " Q{}", naive_cam)?;
+                }
+                Ok(())
+            }
         }
-        {
-            let (_, start_angle, stop_angle) = calculate_angles(
-                Position { x: 30.0, y: 30.0 },
-                Position { x: 40.0, y: 40.0 },
-                Position { x: 10.0, y: 0.0 },
-            );
+    }
+}
 
-            let result = calculate_units(start_angle, stop_angle);
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Spindle {
+    // M3
+    Clockwise,
+    // M4
+    CounterClockwise,
+    // M5
+    Off,
+}
 
-            assert_eq!(Position { x: -1.0, y: 0.0 }, result.0);
-            assert_eq!(Position { x: 0.0, y: 1.0 }, result.1);
+impl fmt::Display for Spindle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Spindle::Clockwise => write!(f, "M3"),
+            Spindle::CounterClockwise => write!(f, "M4"),
+            Spindle::Off => write!(f, "M5"),
         }
     }
+}
 
-    #[test]
-    fn test_calculate_angles() {
-        {
-            let (radius, start_angle, stop_angle) = calculate_angles(
-                Position { x: 10.0, y: 10.0 },
-                Position { x: 20.0, y: 20.0 },
-                Position { x: 0.0, y: 10.0 },
-            );
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Coolant {
+    // M7
+    Mist,
+    // M8
+    Flood,
+    // M9
+    Off,
+}
 
-            assert_eq!(-0.5 * PI, start_angle);
-            assert_eq!(0.0, stop_angle);
-            assert_eq!(10.0, radius);
+impl fmt::Display for Coolant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Coolant::Mist => write!(f, "M7"),
+            Coolant::Flood => write!(f, "M8"),
+            Coolant::Off => write!(f, "M9"),
         }
-        {
-            let (radius, start_angle, stop_angle) = calculate_angles(
-                Position { x: 30.0, y: 30.0 },
-                Position { x: 40.0, y: 40.0 },
-                Position { x: 10.0, y: 0.0 },
-            );
+    }
+}
 
-            assert_eq!(-1.0 * PI, start_angle);
-            assert_eq!(0.5 * PI, stop_angle);
-            assert_eq!(10.0, radius);
+// Motion of the last move block
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MotionMode {
+    // G0
+    Rapid,
+    // G1
+    Linear,
+    // G2
+    ClockwiseArc,
+    // G3
+    CounterClockwiseArc,
+}
+
+impl fmt::Display for MotionMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MotionMode::Rapid => write!(f, "G0"),
+            MotionMode::Linear => write!(f, "G1"),
+            MotionMode::ClockwiseArc => write!(f, "G2"),
+            MotionMode::CounterClockwiseArc => write!(f, "G3"),
         }
     }
 }
 
-pub struct SimpleMachine {
-    program: gcode::GCodeProgram,
-    pc: i32,
-    step: i32,
-    queue: mpsc::Sender<CommandEntry>,
-    sync: mpsc::Receiver<SyncEntry>,
-    config_sync: mpsc::Sender<ToolConfig>,
-    toolstate: ToolState,
-    toolconfig: ToolConfig,
+impl From<Motion> for MotionMode {
+    fn from(motion: Motion) -> Self {
+        match motion {
+            Motion::Linear { rapid: true } => MotionMode::Rapid,
+            Motion::Linear { rapid: false } => MotionMode::Linear,
+            Motion::Arc {
+                clockwise: true, ..
+            } => MotionMode::ClockwiseArc,
+            Motion::Arc { .. } => MotionMode::CounterClockwiseArc,
+        }
+    }
 }
 
-impl SimpleMachine {
-    pub fn new(
-        filepath: String,
-        queue: mpsc::Sender<CommandEntry>,
-        sync: mpsc::Receiver<SyncEntry>,
-        config_sync: mpsc::Sender<ToolConfig>,
-    ) -> SimpleMachine {
-        let construct = SimpleMachine {
-            program: gcode::parse(filepath),
-            pc: 0,
-            step: 1,
-            queue: queue,
-            sync: sync,
-            config_sync: config_sync,
-            toolstate: ToolState::new(),
-            toolconfig: ToolConfig::new(),
-        };
-        construct
-            .config_sync
-            .send(construct.toolconfig.clone())
-            .expect("Sent failed!");
+// Plane arcs are in, the machine only runs G17
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Plane {
+    // G17
+    XY,
+    // G18
+    ZX,
+    // G19
+    YZ,
+}
 
-        return construct;
+impl fmt::Display for Plane {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Plane::XY => write!(f, "G17"),
+            Plane::ZX => write!(f, "G18"),
+            Plane::YZ => write!(f, "G19"),
+        }
     }
+}
 
-    pub fn update_toolstate(entry: &SyncEntry, toolconfig: &ToolConfig, toolstate: &mut ToolState) {
-        toolstate.x += entry.steps_x as f32 / toolconfig.steps_per_unit_x as f32;
-        toolstate.y += entry.steps_y as f32 / toolconfig.steps_per_unit_y as f32;
-        toolstate.z += entry.steps_z as f32 / toolconfig.steps_per_unit_z as f32;
-        toolstate.e += entry.steps_e as f32 / toolconfig.steps_per_unit_e as f32;
-        toolstate.feedrate = entry.rate;
-    }
+// Length units of the program, the machine only runs G21
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Units {
+    // G20
+    Inches,
+    // G21
+    Millimetres,
+}
 
-    fn set_toolstate(&mut self, entry: &SyncEntry) {
-        SimpleMachine::update_toolstate(&entry, &self.toolconfig, &mut self.toolstate);
+impl fmt::Display for Units {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Units::Inches => write!(f, "G20"),
+            Units::Millimetres => write!(f, "G21"),
+        }
     }
+}
 
-    fn process(&mut self) -> i32 {
-        match self.program.get(&self.pc) {
-            Some(entry) => {
-                let command_sent = match &entry[0].command {
-                    // Movement
-                    'G' => match &entry[0].major {
-                        0 => self.movement_interpolated(&entry),
-                        1 => self.movement_interpolated(&entry),
-                        2 => self.movement_arc(&entry, true),
-                        3 => self.movement_arc(&entry, false),
-                        _ => {
-                            println!("Unsupported move: {:?}", entry);
-                            false
-                        }
-                    },
-                    'O' => {
-                        println!("Set name of section");
-                        false
-                    }
-                    _ => {
-                        println!("Unsupported");
-                        false
-                    }
-                };
+// How coordinates are read, the machine only runs G90
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DistanceMode {
+    // G90
+    Absolute,
+    // G91
+    Incremental,
+}
 
-                if command_sent {
-                    // Sync the current toolstate
-                    match self.sync.recv() {
-                        Ok(entry) => {
-                            self.set_toolstate(&entry);
-                            println!("Sync: WorkItem: {:?}, state: {:?}", &entry, &self.toolstate);
-                        }
-                        Err(_) => {
-                            println!("Unable to fetch work item");
-                        }
-                    }
-                }
-                self.pc += self.step;
-                0
-            }
-            None => 1,
+impl fmt::Display for DistanceMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DistanceMode::Absolute => write!(f, "G90"),
+            DistanceMode::Incremental => write!(f, "G91"),
         }
     }
+}
 
-    fn movement_interpolated(&self, parameters: &gcode::GCodeBlock) -> bool {
-        println!("Interpolated movement");
-        let mut next = self.toolstate.clone();
+// Arc tangent to both moves of a corner, the unit vectors in and out of it
+struct CornerArc {
+    start: ToolState,
+    radius: f32,
+    incoming: [f32; 3],
+    outgoing: [f32; 3],
+    points: Vec<ToolState>,
+}
 
-        for parameter in parameters.iter().skip(1) {
-            match parameter.command {
-                'X' => next.x = parameter.major as f32 + parameter.minor,
-                'Y' => next.y = parameter.major as f32 + parameter.minor,
-                'Z' => next.z = parameter.major as f32 + parameter.minor,
-                'E' => next.e = parameter.major as f32 + parameter.minor,
-                'F' => next.feedrate = parameter.major as f32 + parameter.minor,
-                _ => println!("Unsupported parameter, {:?}", parameter),
-            }
-        }
+// Rounded corner between two linear moves
+struct Blend {
+    start: ToolState,
+    points: Vec<ToolState>,
+    segment: planner::Segment,
+}
 
-        let mut current = self.toolstate.clone();
+fn xyz_delta(from: &ToolState, to: &ToolState) -> [f32; 3] {
+    [to.x - from.x, to.y - from.y, to.z - from.z]
+}
 
-        if current.feedrate != next.feedrate {
-            current.feedrate = next.feedrate;
-            self.add_to_queue(CommandEntry {
-                command: Command::Feedrate,
-                value: next.feedrate,
-            });
-        }
+fn length(vector: &[f32; 3]) -> f32 {
+    (vector[0] * vector[0] + vector[1] * vector[1] + vector[2] * vector[2]).sqrt()
+}
 
-        let start_x = FixedResolution::new(current.x, self.toolconfig.steps_per_unit_x);
-        let start_y = FixedResolution::new(current.y, self.toolconfig.steps_per_unit_y);
-        let start_z = FixedResolution::new(current.z, self.toolconfig.steps_per_unit_z);
-        let start_e = FixedResolution::new(current.e, self.toolconfig.steps_per_unit_e);
-        let mut current_x = start_x.clone();
-        let mut current_y = start_y.clone();
-        let mut current_z = start_z.clone();
-        let mut current_e = start_e.clone();
-        let stop_x = FixedResolution::new(next.x, self.toolconfig.steps_per_unit_x);
-        let stop_y = FixedResolution::new(next.y, self.toolconfig.steps_per_unit_y);
-        let stop_z = FixedResolution::new(next.z, self.toolconfig.steps_per_unit_z);
-        let stop_e = FixedResolution::new(next.e, self.toolconfig.steps_per_unit_e);
-
-        let movement_vector = (
-            stop_x.subtract(start_x),
-            stop_y.subtract(start_y),
-            stop_z.subtract(start_z),
-            stop_e.subtract(start_e),
-        );
+fn dot(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
 
-        let movement_amplitude = ((movement_vector.0.raw_value * movement_vector.0.raw_value
-            + movement_vector.1.raw_value * movement_vector.1.raw_value
-            + movement_vector.2.raw_value * movement_vector.2.raw_value
-            + movement_vector.3.raw_value * movement_vector.3.raw_value)
-            as f32)
-            .sqrt();
+// Distance from `point` to the line segment `from` - `to`
+fn distance_to_line(point: &ToolState, from: &ToolState, to: &ToolState) -> f32 {
+    let line = xyz_delta(from, to);
+    let offset = xyz_delta(from, point);
+    let line_length = dot(&line, &line);
+    let t = if line_length > 0.0 {
+        (dot(&offset, &line) / line_length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    length(&[
+        offset[0] - t * line[0],
+        offset[1] - t * line[1],
+        offset[2] - t * line[2],
+    ])
+}
 
-        let mut step = 0;
-        loop {
-            let factor = if movement_amplitude != 0.0 {
-                (step as f32) / movement_amplitude
-            } else {
-                movement_amplitude
-            };
-            let normalized_vector = (
-                start_x.add(movement_vector.0.multiply_raw(factor)),
-                start_y.add(movement_vector.1.multiply_raw(factor)),
-                start_z.add(movement_vector.2.multiply_raw(factor)),
-                start_e.add(movement_vector.3.multiply_raw(factor)),
-            );
+// Arc blending the corner `from` - `corner` - `to` as chords of `resolution`,
+// None when the corner is straight, reversing or too small to round off
+fn corner_arc(
+    from: &ToolState,
+    corner: &ToolState,
+    to: &ToolState,
+    tolerance: Option<f32>,
+    resolution: f32,
+) -> Option<CornerArc> {
+    let incoming = xyz_delta(from, corner);
+    let outgoing = xyz_delta(corner, to);
+    let (incoming_length, outgoing_length) = (length(&incoming), length(&outgoing));
+    if incoming_length <= 0.0 || outgoing_length <= 0.0 {
+        return None;
+    }
+    let u1 = [
+        incoming[0] / incoming_length,
+        incoming[1] / incoming_length,
+        incoming[2] / incoming_length,
+    ];
+    let u2 = [
+        outgoing[0] / outgoing_length,
+        outgoing[1] / outgoing_length,
+        outgoing[2] / outgoing_length,
+    ];
+    let cos_angle = dot(&u1, &u2);
+    if !(-0.9999..=0.9999).contains(&cos_angle) {
+        return None;
+    }
 
-            match current_x.get_direction(&normalized_vector.0) {
-                Some(direction) => {
-                    current_x = current_x.increment(direction);
-                    self.add_to_queue(CommandEntry {
-                        command: Command::StepperX,
-                        value: direction as f32,
-                    })
-                }
-                None => {
-                    if current_x.equal(&stop_x) {
-                        0
-                    } else {
-                        1
-                    }
-                }
-            };
+    // Turning angle, the blend arc sweeps the same angle
+    let angle = cos_angle.acos();
+    let half = 0.5 * angle;
+    let mut distance = 0.5 * incoming_length.min(outgoing_length);
+    if let Some(tolerance) = tolerance {
+        // Largest radius whose midpoint stays within tolerance of the corner
+        let radius = tolerance * half.cos() / (1.0 - half.cos());
+        distance = distance.min(radius * half.tan());
+    }
+    if distance < resolution {
+        return None;
+    }
+    let radius = distance / half.tan();
+
+    let mut start_point = corner.clone();
+    start_point.x -= u1[0] * distance;
+    start_point.y -= u1[1] * distance;
+    start_point.z -= u1[2] * distance;
+    let start = [start_point.x, start_point.y, start_point.z];
+    let inward = [
+        u2[0] - u1[0] * cos_angle,
+        u2[1] - u1[1] * cos_angle,
+        u2[2] - u1[2] * cos_angle,
+    ];
+    let inward_length = length(&inward);
+    let center = [
+        start[0] + inward[0] / inward_length * radius,
+        start[1] + inward[1] / inward_length * radius,
+        start[2] + inward[2] / inward_length * radius,
+    ];
+
+    // Chords deviating at most half a step from the arc
+    let chord_angle = 2.0 * (1.0 - (0.5 * resolution / radius).min(1.0)).acos();
+    let count = ((angle / chord_angle).ceil() as usize).clamp(1, 1000);
+    let points = (1..=count)
+        .map(|index| {
+            let t = angle * index as f32 / count as f32;
+            let mut point = corner.clone();
+            point.x = center[0] + (start[0] - center[0]) * t.cos() + u1[0] * radius * t.sin();
+            point.y = center[1] + (start[1] - center[1]) * t.cos() + u1[1] * radius * t.sin();
+            point.z = center[2] + (start[2] - center[2]) * t.cos() + u1[2] * radius * t.sin();
+            point
+        })
+        .collect();
+    Some(CornerArc {
+        start: start_point,
+        radius,
+        incoming: u1,
+        outgoing: u2,
+        points,
+    })
+}
 
-            match current_y.get_direction(&normalized_vector.1) {
-                Some(direction) => {
-                    current_y = current_y.increment(direction);
-                    self.add_to_queue(CommandEntry {
-                        command: Command::StepperY,
-                        value: direction as f32,
-                    })
-                }
-                None => {
-                    if current_y.equal(&stop_y) {
-                        0
-                    } else {
-                        1
-                    }
-                }
-            };
+// M2 or M30
+fn is_program_end(word: &gcode::GCode) -> bool {
+    word.command == 'M' && word.minor == 0.0 && matches!(word.major, 2 | 30)
+}
 
-            match current_z.get_direction(&normalized_vector.2) {
-                Some(direction) => {
-                    current_z = current_z.increment(direction);
-                    self.add_to_queue(CommandEntry {
-                        command: Command::StepperZ,
-                        value: direction as f32,
-                    })
-                }
-                None => {
-                    if current_z.equal(&stop_z) {
-                        0
-                    } else {
-                        1
+// Whether the block ends the program, wherever the word is in it
+fn program_ends(block: &gcode::GCodeBlock) -> bool {
+    block.iter().any(is_program_end)
+}
+
+// Whether `word` is a parameter a move of `motion` takes
+fn is_move_parameter(word: &gcode::GCode, motion: &Motion) -> bool {
+    matches!(
+        (word.command, motion),
+        ('X' | 'Y' | 'E' | 'F', _) | ('Z', Motion::Linear { .. }) | ('I' | 'J', Motion::Arc { .. })
+    )
+}
+
+// Whether `word` is G`major`.`tenths`, G61.1 is (61, 1)
+fn is_g(word: &gcode::GCode, major: i32, tenths: i32) -> bool {
+    word.command == 'G' && word.major == major && (word.minor * 10.0).round() as i32 == tenths
+}
+
+// The motion G0 to G3 starts, without its parameters
+fn motion_word(word: &gcode::GCode) -> Option<Motion> {
+    if word.command != 'G' || word.minor != 0.0 {
+        return None;
+    }
+    match word.major {
+        0 => Some(Motion::Linear { rapid: true }),
+        1 => Some(Motion::Linear { rapid: false }),
+        2 => Some(Motion::Arc {
+            clockwise: true,
+            center: (0.0, 0.0),
+        }),
+        3 => Some(Motion::Arc {
+            clockwise: false,
+            center: (0.0, 0.0),
+        }),
+        _ => None,
+    }
+}
+
+// Whether the machine runs `word` of `block`, whose move is `motion` if it has one
+fn is_supported(word: &gcode::GCode, block: &gcode::GCodeBlock, motion: Option<&Motion>) -> bool {
+    let has = |major, tenths| block.iter().any(|other| is_g(other, major, tenths));
+    match word.command {
+        'T' | 'S' | 'O' => true,
+        'M' => word.minor == 0.0 && matches!(word.major, 2..=9 | 30),
+        'G' => {
+            motion_word(word).is_some()
+                || [(4, 0), (17, 0), (21, 0), (90, 0), (61, 0), (61, 1), (64, 0)]
+                    .iter()
+                    .any(|&(major, tenths)| is_g(word, major, tenths))
+        }
+        'P' if has(4, 0) || has(64, 0) => true,
+        'Q' if has(64, 0) => true,
+        _ => motion.is_some_and(|motion| is_move_parameter(word, motion)),
+    }
+}
+
+// Resolves a motion block against the state it starts from, None for any other block
+fn parse_move(block: &gcode::GCodeBlock, from: &ToolState) -> Option<(Motion, ToolState)> {
+    let mut motion = block.iter().find_map(motion_word)?;
+
+    let mut target = from.clone();
+    for parameter in block.iter() {
+        let value = parameter.major as f32 + parameter.minor;
+        match (parameter.command, &mut motion) {
+            ('X', _) => target.x = value,
+            ('Y', _) => target.y = value,
+            ('Z', Motion::Linear { .. }) => target.z = value,
+            ('E', _) => target.e = value,
+            ('F', _) => target.feedrate = value,
+            ('I', Motion::Arc { center, .. }) => center.0 = value,
+            ('J', Motion::Arc { center, .. }) => center.1 = value,
+            _ => {}
+        }
+    }
+    Some((motion, target))
+}
+
+// What a motion block does, straight down or up moves count as plunge and
+// retract whether they are rapid or not
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum MoveKind {
+    Rapid,
+    Feed,
+    Arc,
+    Plunge,
+    Retract,
+}
+
+// Programmed path of one block, machine coordinates
+#[derive(Debug, PartialEq, Clone)]
+pub struct PathSegment {
+    pub block: i32,
+    pub kind: MoveKind,
+    pub points: Vec<[f32; 3]>,
+}
+
+// Box around the tool positions of a program, machine coordinates
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Bounds {
+    pub fn new(point: [f32; 3]) -> Self {
+        Bounds {
+            min: point,
+            max: point,
+        }
+    }
+
+    pub fn extend(&mut self, point: [f32; 3]) {
+        for (axis, value) in point.iter().enumerate() {
+            self.min[axis] = self.min[axis].min(*value);
+            self.max[axis] = self.max[axis].max(*value);
+        }
+    }
+
+    pub fn size(&self) -> [f32; 3] {
+        [
+            self.max[0] - self.min[0],
+            self.max[1] - self.min[1],
+            self.max[2] - self.min[2],
+        ]
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PlannedSegment {
+    pub block: i32,
+    pub profile: VelocityProfile,
+}
+
+impl fmt::Display for PlannedSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "N{}: {:.3} long, entry {:.0}, cruise {:.0}, exit {:.0} units/min, {:.3} s",
+            self.block,
+            self.profile.length,
+            self.profile.entry_velocity * 60.0,
+            self.profile.cruise_velocity * 60.0,
+            self.profile.exit_velocity * 60.0,
+            self.profile.duration()
+        )
+    }
+}
+
+#[cfg(test)]
+fn calculate_units(start_angle: f32, stop_angle: f32) -> (Position, Position) {
+    let start_unit = Position::new(start_angle.cos(), start_angle.sin());
+    let stop_unit = Position::new(stop_angle.cos(), stop_angle.sin());
+    (start_unit, stop_unit)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MachineStatus {
+    Running,
+    Finished,
+    Halted,
+    // Stopped on the breakpoint or watchpoint with this id, runs on when stepped
+    Break(usize),
+}
+
+pub struct SimpleMachine {
+    program: gcode::GCodeProgram,
+    // Text the program was loaded from, empty when loaded already parsed
+    listing: gcode::Listing,
+    pc: i32,
+    step: i32,
+    stepper: Stepper,
+    // Steps and time of the last move, once the stepper is done with it
+    sync: Option<SyncEntry>,
+    sinks: Vec<Box<dyn MachineSink>>,
+    // Machine position in steps per axis, the tool state has it in units
+    steps: [i64; 4],
+    toolstate: ToolState,
+    toolconfig: ToolConfig,
+    violations: Vec<SoftLimitViolation>,
+    halted: bool,
+    // Past an M2 or M30, the blocks after it do not run
+    ended: bool,
+    // Exit velocity of the previous move, None when it came to rest
+    entry_velocity: Option<f32>,
+    planned: Vec<PlannedSegment>,
+    motion_mode: MotionMode,
+    plane: Plane,
+    units: Units,
+    distance_mode: DistanceMode,
+    path_mode: PathMode,
+    selected_tool: i32,
+    // Programmed S, rev/min
+    spindle_speed: f32,
+    spindle: Spindle,
+    coolant: Coolant,
+    overrides: Overrides,
+    estimate: CycleTimeEstimate,
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint: usize,
+    hit: Option<usize>,
+    // Block a breakpoint stopped before, it runs when resumed
+    resume_at: Option<i32>,
+    history: History,
+    // Also among the sinks, kept to close it
+    signal_dump: Option<Rc<RefCell<VcdWriter<BufWriter<File>>>>>,
+}
+
+impl SimpleMachine {
+    pub fn new(toolconfig: ToolConfig) -> SimpleMachine {
+        let mut machine = SimpleMachine {
+            program: gcode::GCodeProgram::new(),
+            listing: gcode::Listing::new(),
+            pc: 0,
+            step: 1,
+            stepper: Stepper::new(&toolconfig),
+            sync: None,
+            sinks: vec![],
+            steps: [0; 4],
+            toolstate: ToolState::new(),
+            toolconfig,
+            violations: vec![],
+            halted: false,
+            ended: false,
+            entry_velocity: None,
+            planned: vec![],
+            motion_mode: MotionMode::Rapid,
+            plane: Plane::XY,
+            units: Units::Millimetres,
+            distance_mode: DistanceMode::Absolute,
+            path_mode: PathMode::ExactPath,
+            selected_tool: 0,
+            spindle_speed: 0.0,
+            spindle: Spindle::Off,
+            coolant: Coolant::Off,
+            overrides: Overrides::new(),
+            estimate: CycleTimeEstimate::new(),
+            breakpoints: vec![],
+            next_breakpoint: 1,
+            hit: None,
+            resume_at: None,
+            history: History::new(),
+            signal_dump: None,
+        };
+        machine
+            .history
+            .set_tolerance(machine.toolconfig.step_size());
+        machine.history.record(machine.snapshot());
+        machine
+    }
+
+    // Starts over with `program` from its first block
+    pub fn load(&mut self, program: gcode::GCodeProgram) {
+        let toolconfig = self.toolconfig.clone();
+        let sinks = std::mem::take(&mut self.sinks);
+        let signal_dump = self.signal_dump.take();
+        let mut breakpoints = std::mem::take(&mut self.breakpoints);
+        let next_breakpoint = self.next_breakpoint;
+        let overrides = self.overrides;
+        *self = SimpleMachine::new(toolconfig);
+        self.program = program;
+        self.overrides = overrides;
+        self.sinks = sinks;
+        self.signal_dump = signal_dump;
+        for breakpoint in breakpoints.iter_mut() {
+            breakpoint.active = false;
+        }
+        self.breakpoints = breakpoints;
+        self.next_breakpoint = next_breakpoint;
+    }
+
+    pub fn load_str(&mut self, contents: &str) {
+        self.load(gcode::parse_str(contents));
+        self.listing = gcode::listing(contents);
+    }
+
+    pub fn load_file(&mut self, filepath: &str) -> io::Result<()> {
+        let contents = std::fs::read_to_string(filepath)?;
+        self.load_str(&contents);
+        Ok(())
+    }
+
+    // Sinks get every event from now on, in the order they were added
+    pub fn add_sink(&mut self, mut sink: Box<dyn MachineSink>) {
+        sink.config(&self.toolconfig);
+        self.sinks.push(sink);
+    }
+
+    // Applies to the moves that follow
+    pub fn set_toolconfig(&mut self, toolconfig: ToolConfig) {
+        self.stepper
+            .step_rates
+            .set_limits(toolconfig.max_step_frequency());
+        for sink in self.sinks.iter_mut() {
+            sink.config(&toolconfig);
+        }
+        self.history.set_tolerance(toolconfig.step_size());
+        self.toolconfig = toolconfig;
+        self.steps = self.units_to_steps(&self.toolstate);
+    }
+
+    // Writes the step and dir signals as a Value Change Dump while running
+    pub fn dump_signals(&mut self, path: &str) -> io::Result<()> {
+        let vcd = VcdWriter::new(
+            BufWriter::new(File::create(path)?),
+            self.toolconfig.step_pulse_width,
+            self.toolconfig.dir_setup_time,
+        )?;
+        let vcd = Rc::new(RefCell::new(vcd));
+        self.add_sink(Box::new(vcd.clone()));
+        self.signal_dump = Some(vcd);
+        Ok(())
+    }
+
+    // Ends the signal dump wherever the run stopped, with the first failure
+    // writing it
+    pub fn close_signal_dump(&mut self) -> io::Result<()> {
+        match self.signal_dump.take() {
+            Some(vcd) => vcd.borrow_mut().close(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn overrides(&self) -> &Overrides {
+        &self.overrides
+    }
+
+    // Applies to the blocks executed from now on
+    pub fn set_overrides(&mut self, overrides: Overrides) {
+        self.overrides = overrides.clamped();
+        self.message(format!(
+            "Overrides: feed {}%, rapid {}%, spindle {}%",
+            self.overrides.feed, self.overrides.rapid, self.overrides.spindle
+        ));
+    }
+
+    // Programmed feedrate with the feed override, units/min
+    pub fn effective_feedrate(&self) -> f32 {
+        self.toolstate.feedrate * self.overrides.feed / 100.0
+    }
+
+    // Programmed spindle speed with the spindle override, rev/min
+    pub fn effective_spindle_speed(&self) -> f32 {
+        self.spindle_speed * self.overrides.spindle / 100.0
+    }
+
+    pub fn listing(&self) -> &gcode::Listing {
+        &self.listing
+    }
+
+    pub fn toolstate(&self) -> &ToolState {
+        &self.toolstate
+    }
+
+    pub fn toolconfig(&self) -> &ToolConfig {
+        &self.toolconfig
+    }
+
+    // Block about to be executed
+    pub fn current_block(&self) -> i32 {
+        self.pc
+    }
+
+    pub fn status(&self) -> MachineStatus {
+        if self.halted {
+            MachineStatus::Halted
+        } else if let Some(id) = self.hit {
+            MachineStatus::Break(id)
+        } else if self.has_next_block() {
+            MachineStatus::Running
+        } else {
+            MachineStatus::Finished
+        }
+    }
+
+    fn has_next_block(&self) -> bool {
+        !self.ended && self.program.contains_key(&self.pc)
+    }
+
+    // Executes a single block, unless a breakpoint stops before it
+    pub fn step_block(&mut self) -> MachineStatus {
+        self.hit = None;
+        let resuming = self.resume_at.take() == Some(self.pc);
+        match self.status() {
+            MachineStatus::Running => {
+                let block = self.pc;
+                if !resuming {
+                    if let Some(index) = self.breakpoint_before(block) {
+                        self.resume_at = Some(block);
+                        self.stop_at(block, index);
+                        return self.status();
+                    }
+                }
+
+                self.discard_future();
+                self.process();
+                self.history.record(self.snapshot());
+                if let Some(index) = self.watchpoint_after() {
+                    self.stop_at(block, index);
+                }
+
+                let status = if self.halted {
+                    MachineStatus::Halted
+                } else if self.has_next_block() {
+                    MachineStatus::Running
+                } else {
+                    MachineStatus::Finished
+                };
+                if status != MachineStatus::Running {
+                    for sink in self.sinks.iter_mut() {
+                        sink.finished(status);
+                    }
+                }
+                self.status()
+            }
+            status => status,
+        }
+    }
+
+    // Returns the id to remove it by
+    pub fn add_breakpoint(&mut self, condition: Condition) -> usize {
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        let mut breakpoint = Breakpoint::new(id, condition);
+        breakpoint.active = breakpoint.condition.matches_state(&self.toolstate);
+        self.breakpoints.push(breakpoint);
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() != count
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    fn breakpoint_before(&self, label: i32) -> Option<usize> {
+        let block = self.program.get(&label)?;
+        self.breakpoints.iter().position(|breakpoint| {
+            breakpoint.enabled
+                && !breakpoint.condition.is_watchpoint()
+                && breakpoint.condition.matches_block(label, block)
+        })
+    }
+
+    // First watchpoint whose condition became true
+    fn watchpoint_after(&mut self) -> Option<usize> {
+        let mut fired = None;
+        for (index, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            let active = breakpoint.condition.matches_state(&self.toolstate);
+            if active && !breakpoint.active && breakpoint.enabled && fired.is_none() {
+                fired = Some(index);
+            }
+            breakpoint.active = active;
+        }
+        fired
+    }
+
+    fn stop_at(&mut self, block: i32, index: usize) {
+        let breakpoint = self.breakpoints[index].clone();
+        self.hit = Some(breakpoint.id);
+        for sink in self.sinks.iter_mut() {
+            sink.stopped(block, &breakpoint);
+        }
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn modal_state(&self) -> ModalState {
+        ModalState {
+            motion_mode: self.motion_mode,
+            plane: self.plane,
+            units: self.units,
+            distance_mode: self.distance_mode,
+            path_mode: self.path_mode,
+            selected_tool: self.selected_tool,
+            spindle_speed: self.spindle_speed,
+            spindle: self.spindle,
+            coolant: self.coolant,
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            block: self.pc,
+            state: self.toolstate.clone(),
+            modal: self.modal_state(),
+            halted: self.halted,
+            ended: self.ended,
+            entry_velocity: self.entry_velocity,
+            step_rates: self.stepper.step_rates.clone(),
+            samples: self.history.motion().len(),
+            planned: self.planned.len(),
+            timed_blocks: self.estimate.blocks.len(),
+            violations: self.violations.len(),
+        }
+    }
+
+    // Puts the machine back to where it was before a recorded block, without
+    // executing anything. The sinks are told, events continue from there.
+    fn restore(&mut self, index: usize) {
+        let snapshot = self.history.snapshots()[index].clone();
+        self.pc = snapshot.block;
+        self.toolstate = snapshot.state.clone();
+        self.steps = self.units_to_steps(&snapshot.state);
+        self.motion_mode = snapshot.modal.motion_mode;
+        self.plane = snapshot.modal.plane;
+        self.units = snapshot.modal.units;
+        self.distance_mode = snapshot.modal.distance_mode;
+        self.path_mode = snapshot.modal.path_mode;
+        self.selected_tool = snapshot.modal.selected_tool;
+        self.spindle_speed = snapshot.modal.spindle_speed;
+        self.spindle = snapshot.modal.spindle;
+        self.coolant = snapshot.modal.coolant;
+        self.halted = snapshot.halted;
+        self.ended = snapshot.ended;
+        self.entry_velocity = snapshot.entry_velocity;
+        self.hit = None;
+        self.resume_at = None;
+
+        let syncentry = &mut self.stepper.syncentry;
+        *syncentry = SyncEntry::new();
+        syncentry.rate = self.toolstate.feedrate;
+        syncentry.velocity = self.toolstate.velocity;
+        syncentry.tool = self.toolstate.tool;
+        self.stepper.step_rates.end_move();
+
+        for breakpoint in self.breakpoints.iter_mut() {
+            breakpoint.active = breakpoint.condition.matches_state(&self.toolstate);
+        }
+        self.history.set_current(index);
+        for sink in self.sinks.iter_mut() {
+            sink.jumped(&snapshot);
+        }
+    }
+
+    // Executing after a jump back replaces what was recorded past that point
+    fn discard_future(&mut self) {
+        if let Some(snapshot) = self.history.branch() {
+            self.planned.truncate(snapshot.planned);
+            self.estimate.blocks.truncate(snapshot.timed_blocks);
+            self.violations.truncate(snapshot.violations);
+            let limits = self.stepper.step_rates.limits();
+            self.stepper.step_rates = snapshot.step_rates;
+            self.stepper.step_rates.set_limits(limits);
+        }
+    }
+
+    // Back to the start of the block executed before the current one
+    pub fn step_back(&mut self) -> MachineStatus {
+        let current = self.history.current();
+        if current > 0 {
+            self.restore(current - 1);
+        }
+        self.status()
+    }
+
+    // To the start of `block`, executing up to it if it has not run yet
+    pub fn jump_to_block(&mut self, block: i32) -> MachineStatus {
+        if self.history.find_block(block).is_none() {
+            self.restore(self.history.snapshots().len() - 1);
+            while self.status() == MachineStatus::Running && self.pc != block {
+                self.step_block();
+            }
+        }
+        if let Some(index) = self.history.find_block(block) {
+            self.restore(index);
+        }
+        self.status()
+    }
+
+    // To the start of the block executing `time` seconds into the program,
+    // executing up to it if it has not run yet
+    pub fn jump_to_time(&mut self, time: f32) -> MachineStatus {
+        if self.history.end_time() <= time {
+            self.restore(self.history.snapshots().len() - 1);
+            while self.status() == MachineStatus::Running && self.toolstate.time <= time {
+                self.step_block();
+            }
+        }
+        if let Some(index) = self.history.find_time(time) {
+            self.restore(index);
+        }
+        self.status()
+    }
+
+    pub fn run_to_end(&mut self) -> MachineStatus {
+        while self.step_block() == MachineStatus::Running {}
+        self.status()
+    }
+
+    pub fn step_rates(&self) -> &StepRateMonitor {
+        &self.stepper.step_rates
+    }
+
+    // Takes in the steps and time of a finished move. The position is counted
+    // in steps, the tool state only shows it in units.
+    fn set_toolstate(&mut self, entry: &SyncEntry) {
+        let moved = [entry.steps_x, entry.steps_y, entry.steps_z, entry.steps_e];
+        for (steps, moved) in self.steps.iter_mut().zip(moved.iter()) {
+            *steps += *moved as i64;
+        }
+        let position = self.steps_to_units(&self.steps);
+        let toolstate = &mut self.toolstate;
+        toolstate.x = position[0];
+        toolstate.y = position[1];
+        toolstate.z = position[2];
+        toolstate.e = position[3];
+        toolstate.feedrate = entry.rate;
+        toolstate.velocity = entry.velocity;
+        toolstate.time += entry.elapsed;
+        toolstate.tool = entry.tool;
+    }
+
+    fn steps_to_units(&self, steps: &[i64; 4]) -> [f32; 4] {
+        let steps_per_unit = self.toolconfig.steps_per_unit();
+        let mut units = [0.0; 4];
+        for axis in 0..4 {
+            units[axis] = (steps[axis] as f64 / steps_per_unit[axis] as f64) as f32;
+        }
+        units
+    }
+
+    fn units_to_steps(&self, state: &ToolState) -> [i64; 4] {
+        let steps_per_unit = self.toolconfig.steps_per_unit();
+        let units = [state.x, state.y, state.z, state.e];
+        let mut steps = [0; 4];
+        for axis in 0..4 {
+            steps[axis] = to_fixed(units[axis], steps_per_unit[axis]);
+        }
+        steps
+    }
+
+    pub fn soft_limit_violations(&self) -> &[SoftLimitViolation] {
+        &self.violations
+    }
+
+    // Records every axis position outside the travel, returns false if the move must not run
+    fn check_soft_limits(&mut self, positions: &[(char, f32)]) -> bool {
+        let mut within_limits = true;
+        for &(axis, position) in positions {
+            let overshoot = match self.toolconfig.travel(axis) {
+                Some(limits) => limits.overshoot(position),
+                None => None,
+            };
+            if let Some(overshoot) = overshoot {
+                let violation = SoftLimitViolation {
+                    block: self.pc,
+                    axis,
+                    position,
+                    overshoot,
+                };
+                self.error(MachineError::SoftLimit(violation.clone()));
+                self.violations.push(violation);
+                within_limits = false;
+            }
+        }
+
+        if !within_limits && self.toolconfig.halt_on_soft_limit {
+            self.halted = true;
+        }
+        !self.halted
+    }
+
+    fn process(&mut self) -> i32 {
+        match self.program.get(&self.pc).cloned() {
+            Some(entry) => {
+                self.execute(&entry);
+                for sink in self.sinks.iter_mut() {
+                    sink.state(self.pc, &self.toolstate);
+                }
+
+                if self.halted {
+                    self.error(MachineError::Halted(self.pc));
+                    return 2;
+                }
+                self.pc += self.step;
+                0
+            }
+            None => 1,
+        }
+    }
+
+    // Motion blocks in the order they run, with the state each starts from
+    fn program_moves(&self) -> Vec<(i32, Motion, ToolState, ToolState)> {
+        let mut moves = vec![];
+        let mut state = ToolState::new();
+        let mut pc = 0;
+        while let Some(block) = self.program.get(&pc) {
+            if let Some((motion, target)) = parse_move(block, &state) {
+                moves.push((pc, motion, state, target.clone()));
+                state = target;
+            }
+            if program_ends(block) {
+                break;
+            }
+            pc += self.step;
+        }
+        moves
+    }
+
+    // Where the program takes the tool, from the blocks alone without executing them
+    pub fn program_bounds(&self) -> Bounds {
+        let start = ToolState::new();
+        let mut bounds = Bounds::new([start.x, start.y, start.z]);
+        for (_, motion, from, target) in self.program_moves() {
+            match motion {
+                Motion::Arc { clockwise, center } => {
+                    let (radius, start_angle, stop_angle) =
+                        arc_sweep(&from, &target, center, clockwise);
+                    let (min, max) = arc_extents(
+                        (from.x + center.0, from.y + center.1),
+                        radius,
+                        start_angle,
+                        stop_angle,
+                    );
+                    bounds.extend([min.0, min.1, target.z]);
+                    bounds.extend([max.0, max.1, target.z]);
+                }
+                Motion::Linear { .. } => bounds.extend([target.x, target.y, target.z]),
+            }
+        }
+        bounds
+    }
+
+    // Programmed path of every motion block, arcs split into chords of at most 5 degrees
+    pub fn toolpath(&self) -> Vec<PathSegment> {
+        let mut path = vec![];
+        for (block, motion, from, target) in self.program_moves() {
+            let start = [from.x, from.y, from.z];
+            let (kind, points) = match motion {
+                Motion::Arc { clockwise, center } => {
+                    let (radius, start_angle, stop_angle) =
+                        arc_sweep(&from, &target, center, clockwise);
+                    let sweep = stop_angle - start_angle;
+                    let chords = (sweep.abs() / (PI / 36.0)).ceil().max(1.0) as usize;
+                    let mut points = vec![start];
+                    for chord in 1..=chords {
+                        let angle = start_angle + sweep * chord as f32 / chords as f32;
+                        points.push([
+                            from.x + center.0 + radius * angle.cos(),
+                            from.y + center.1 + radius * angle.sin(),
+                            from.z,
+                        ]);
                     }
+                    (MoveKind::Arc, points)
+                }
+                Motion::Linear { rapid } => {
+                    let vertical = target.x == from.x && target.y == from.y;
+                    let kind = if vertical && target.z < from.z {
+                        MoveKind::Plunge
+                    } else if vertical && target.z > from.z {
+                        MoveKind::Retract
+                    } else if rapid {
+                        MoveKind::Rapid
+                    } else {
+                        MoveKind::Feed
+                    };
+                    (kind, vec![start, [target.x, target.y, target.z]])
                 }
             };
+            path.push(PathSegment {
+                block,
+                kind,
+                points,
+            });
+        }
+        path
+    }
+
+    pub fn planned_segments(&self) -> &[PlannedSegment] {
+        &self.planned
+    }
+
+    pub fn cycle_time(&self) -> &CycleTimeEstimate {
+        &self.estimate
+    }
+
+    // Runs the words of a block in the order RS274/NGC gives them, whatever
+    // order they are written in: tool, spindle and coolant words, dwell, plane,
+    // units and distance mode, path control, motion and last the program end.
+    // The words nothing runs are reported.
+    fn execute(&mut self, block: &gcode::GCodeBlock) {
+        if self.modal_words(block) {
+            self.sync_toolstate();
+        }
+        if block.iter().any(|word| is_g(word, 4, 0)) && self.dwell(block) {
+            self.sync_toolstate();
+        }
+        for word in block.iter() {
+            self.set_coordinate_mode(word);
+            self.set_path_mode(word, block);
+        }
+
+        let motion = parse_move(block, &self.toolstate);
+        let unsupported: gcode::GCodeBlock = block
+            .iter()
+            .filter(|word| !is_supported(word, block, motion.as_ref().map(|(motion, _)| motion)))
+            .cloned()
+            .collect();
+        if !unsupported.is_empty() {
+            self.error(MachineError::Unsupported(unsupported));
+        }
+        if block.iter().any(|word| word.command == 'O') {
+            self.message("Set name of section".to_string());
+        }
+
+        let command_sent = match motion {
+            Some((motion @ Motion::Linear { rapid }, target)) => {
+                self.motion_mode = MotionMode::from(motion);
+                self.movement_interpolated(target, rapid)
+            }
+            Some((motion @ Motion::Arc { clockwise, center }, target)) => {
+                self.motion_mode = MotionMode::from(motion);
+                self.movement_arc(target, center, clockwise)
+            }
+            None => false,
+        };
+        if command_sent {
+            self.sync_toolstate();
+        }
+
+        if program_ends(block) {
+            self.program_end();
+        }
+    }
+
+    // Takes the tool state the stepper reached at the end of a command
+    fn sync_toolstate(&mut self) {
+        match self.sync.take() {
+            Some(entry) => self.set_toolstate(&entry),
+            None => self.message("Unable to fetch work item".to_string()),
+        }
+    }
+
+    // G4, dwell for P seconds
+    fn dwell(&mut self, block: &gcode::GCodeBlock) -> bool {
+        let seconds = match block.iter().find(|word| word.command == 'P') {
+            Some(word) => word.major as f32 + word.minor,
+            None => 0.0,
+        };
+        self.message(format!("Dwell {:.3} s", seconds));
+        self.estimate
+            .add_dwell(self.pc, self.toolstate.tool, seconds);
+
+        self.add_to_queue(CommandEntry {
+            command: Command::Elapsed,
+            value: seconds,
+            time: 0.0,
+        });
+        self.add_to_queue(CommandEntry {
+            command: Command::Done,
+            value: 0.0,
+            time: 0.0,
+        });
+        true
+    }
+
+    // T selects the next tool, M6 swaps it into the spindle, S sets its speed,
+    // M3 to M5 start and stop the spindle and M7 to M9 the coolant. The other
+    // words of the block are left to the rest of `execute`.
+    fn modal_words(&mut self, block: &gcode::GCodeBlock) -> bool {
+        let mut change = false;
+        for word in block.iter() {
+            if word.command == 'M' && word.minor != 0.0 {
+                continue;
+            }
+            match (word.command, word.major) {
+                ('T', tool) => {
+                    self.message(format!("Select tool T{}", tool));
+                    self.selected_tool = tool;
+                }
+                ('M', 6) => change = true,
+                ('M', 3) | ('M', 4) | ('M', 5) => {
+                    self.spindle = match word.major {
+                        3 => Spindle::Clockwise,
+                        4 => Spindle::CounterClockwise,
+                        _ => Spindle::Off,
+                    };
+                    self.message(format!("Spindle {:?}", self.spindle));
+                }
+                ('M', 7) | ('M', 8) | ('M', 9) => {
+                    self.coolant = match word.major {
+                        7 => Coolant::Mist,
+                        8 => Coolant::Flood,
+                        _ => Coolant::Off,
+                    };
+                    self.message(format!("Coolant {:?}", self.coolant));
+                }
+                ('S', _) => {
+                    self.spindle_speed = word.major as f32 + word.minor;
+                    self.message(format!(
+                        "Spindle speed S{} ({} rev/min with override)",
+                        self.spindle_speed,
+                        self.effective_spindle_speed()
+                    ));
+                }
+                _ => {}
+            }
+        }
+        change && self.tool_change()
+    }
+
+    // M2 or M30, stops the spindle and coolant, nothing after the block runs
+    fn program_end(&mut self) {
+        self.message("Program end".to_string());
+        self.spindle = Spindle::Off;
+        self.coolant = Coolant::Off;
+        self.ended = true;
+    }
+
+    // Swaps the selected tool into the spindle
+    fn tool_change(&mut self) -> bool {
+        let seconds = self.toolconfig.tool_change_time;
+        self.message(format!("Tool change to T{}", self.selected_tool));
+        self.estimate
+            .add_tool_change(self.pc, self.selected_tool, seconds);
+
+        self.add_to_queue(CommandEntry {
+            command: Command::Tool,
+            value: self.selected_tool as f32,
+            time: 0.0,
+        });
+        self.add_to_queue(CommandEntry {
+            command: Command::Elapsed,
+            value: seconds,
+            time: 0.0,
+        });
+        self.add_to_queue(CommandEntry {
+            command: Command::Done,
+            value: 0.0,
+            time: 0.0,
+        });
+        true
+    }
+
+    fn segment(&self, from: &ToolState, motion: &Motion, target: &ToolState) -> planner::Segment {
+        match motion {
+            Motion::Linear { rapid } => {
+                let delta = [
+                    target.x - from.x,
+                    target.y - from.y,
+                    target.z - from.z,
+                    target.e - from.e,
+                ];
+                let cartesian =
+                    (delta[0] * delta[0] + delta[1] * delta[1] + delta[2] * delta[2]).sqrt();
+                // Extruder-only moves are measured along the extruder axis
+                let length = if cartesian > 0.0 {
+                    cartesian
+                } else {
+                    delta[3].abs()
+                };
+                let direction = if length > 0.0 {
+                    [
+                        delta[0] / length,
+                        delta[1] / length,
+                        delta[2] / length,
+                        delta[3] / length,
+                    ]
+                } else {
+                    [0.0; 4]
+                };
+                let dynamics = AxisDynamics::along(&self.toolconfig.dynamics(), direction);
+                let feedrate = if *rapid {
+                    dynamics.max_velocity * self.overrides.rapid / 100.0
+                } else {
+                    self.feed(target.feedrate)
+                };
+                planner::Segment::new(length, direction, direction, feedrate, dynamics)
+            }
+            Motion::Arc { clockwise, center } => {
+                let (radius, start_angle, stop_angle) =
+                    arc_sweep(from, target, *center, *clockwise);
+                let tangent = |angle: f32| {
+                    if *clockwise {
+                        [angle.sin(), -angle.cos(), 0.0, 0.0]
+                    } else {
+                        [-angle.sin(), angle.cos(), 0.0, 0.0]
+                    }
+                };
+
+                // The arc sweeps through both X and Y, bound it by the slower of the two
+                let dynamics = self
+                    .toolconfig
+                    .dynamics_x
+                    .combined(&self.toolconfig.dynamics_y);
+                // Centripetal acceleration bounds the velocity around the arc
+                let centripetal = (dynamics.max_acceleration * radius).sqrt();
+                planner::Segment::new(
+                    radius * (stop_angle - start_angle).abs(),
+                    tangent(start_angle),
+                    tangent(stop_angle),
+                    self.feed(target.feedrate).min(centripetal),
+                    dynamics,
+                )
+            }
+        }
+    }
+
+    // Programmed feedrate in units/min to the overridden one in units/s
+    fn feed(&self, feedrate: f32) -> f32 {
+        feedrate * self.overrides.feed / 100.0 / 60.0
+    }
+
+    // Plans the first of `segments`, the pieces left of the executing block,
+    // against the rest of them and the moves following the block's `end` in the
+    // program. Its exit velocity becomes the entry velocity of the next piece
+    fn plan_profile(
+        &mut self,
+        mut segments: Vec<planner::Segment>,
+        end: &ToolState,
+    ) -> VelocityProfile {
+        let segment = segments[0];
+        if segment.length <= 0.0 {
+            return segment.profile(0.0, 0.0);
+        }
+        segments.retain(|pending| pending.length > 0.0);
+
+        let lookahead = match self.path_mode {
+            PathMode::ExactStop => 0,
+            _ => self.toolconfig.lookahead,
+        };
+        let mut position = end.clone();
+        let mut pc = self.pc;
+        while segments.len() <= lookahead {
+            pc += self.step;
+            let (motion, target) = match self
+                .program
+                .get(&pc)
+                .and_then(|block| parse_move(block, &position))
+            {
+                Some(next) => next,
+                None => break,
+            };
 
-            match current_e.get_direction(&normalized_vector.3) {
-                Some(direction) => {
-                    current_e = current_e.increment(direction);
+            let next = self.segment(&position, &motion, &target);
+            // Zero length moves, such as a lone feedrate, do not form a junction
+            if next.length > 0.0 {
+                segments.push(next);
+            }
+            position = target;
+        }
+
+        let junction = self.toolconfig.junction;
+        let entry = match self.entry_velocity {
+            Some(velocity) => velocity,
+            None => junction.standstill_velocity(&segment),
+        };
+        let exit = junction.exit_velocity(&self.toolconfig.dynamics(), entry, &segments);
+        self.entry_velocity = if segments.len() > 1 { Some(exit) } else { None };
+
+        let profile = segment.profile(entry, exit);
+        let planned = PlannedSegment {
+            block: self.pc,
+            profile,
+        };
+        for sink in self.sinks.iter_mut() {
+            sink.segment(&planned);
+        }
+        self.planned.push(planned);
+        self.estimate
+            .add_motion(self.pc, self.toolstate.tool, profile.duration());
+        profile
+    }
+
+    // Reports the planned velocity at `distance` into the segment whenever it
+    // changes, together with the time spent since the previous report
+    fn follow_profile(
+        &mut self,
+        profile: &VelocityProfile,
+        distance: f32,
+        reported: &mut (f32, f32),
+    ) {
+        let velocity = (profile.velocity_at(distance) * 60.0).round();
+        if velocity != reported.0 {
+            let time = profile.time_at(distance);
+            self.add_to_queue(CommandEntry {
+                command: Command::Elapsed,
+                value: time - reported.1,
+                time: 0.0,
+            });
+            self.add_to_queue(CommandEntry {
+                command: Command::Velocity,
+                value: velocity,
+                time: 0.0,
+            });
+            *reported = (velocity, time);
+        }
+    }
+
+    // Timestamp of a step `distance` into the profile of the executing move.
+    // A block can run several profiles one after the other, the time already
+    // reported for the move puts this one after them.
+    fn step_time(&self, profile: &VelocityProfile, distance: f32, reported: &(f32, f32)) -> f64 {
+        let elapsed = self.stepper.syncentry.elapsed as f64;
+        self.toolstate.time as f64 + elapsed + (profile.time_at(distance) - reported.1) as f64
+    }
+
+    fn finish_profile(&mut self, profile: &VelocityProfile, reported: &(f32, f32)) {
+        self.add_to_queue(CommandEntry {
+            command: Command::Elapsed,
+            value: profile.duration() - reported.1,
+            time: 0.0,
+        });
+        self.add_to_queue(CommandEntry {
+            command: Command::Velocity,
+            value: (profile.exit_velocity * 60.0).round(),
+            time: 0.0,
+        });
+    }
+
+    // Steps along a straight line from `from` to `to`, covering `length` of
+    // `profile` starting `start_distance` into it
+    fn emit_line(
+        &mut self,
+        from: &ToolState,
+        to: &ToolState,
+        profile: &VelocityProfile,
+        start_distance: f32,
+        length: f32,
+        reported: &mut (f32, f32),
+    ) {
+        let start = [
+            FixedResolution::new(from.x, self.toolconfig.steps_per_unit_x),
+            FixedResolution::new(from.y, self.toolconfig.steps_per_unit_y),
+            FixedResolution::new(from.z, self.toolconfig.steps_per_unit_z),
+            FixedResolution::new(from.e, self.toolconfig.steps_per_unit_e),
+        ];
+        let stop = [
+            FixedResolution::new(to.x, self.toolconfig.steps_per_unit_x),
+            FixedResolution::new(to.y, self.toolconfig.steps_per_unit_y),
+            FixedResolution::new(to.z, self.toolconfig.steps_per_unit_z),
+            FixedResolution::new(to.e, self.toolconfig.steps_per_unit_e),
+        ];
+        let mut delta = [0; 4];
+        for axis in 0..4 {
+            delta[axis] = stop[axis].subtract(start[axis]).raw_value;
+        }
+
+        let generator = StepGenerator::new(delta);
+        let ticks = generator.ticks() as f32;
+        for (tick, step) in generator.enumerate() {
+            let distance = start_distance + (tick + 1) as f32 / ticks * length;
+            self.follow_profile(profile, distance, reported);
+            let time = self.step_time(profile, distance, reported);
+
+            for (axis, direction) in step.iter().enumerate() {
+                if *direction != 0 {
                     self.add_to_queue(CommandEntry {
-                        command: Command::StepperE,
-                        value: direction as f32,
-                    })
-                }
-                None => {
-                    if current_e.equal(&stop_e) {
-                        0
-                    } else {
-                        1
-                    }
+                        command: Command::stepper(axis),
+                        value: *direction as f32,
+                        time,
+                    });
                 }
+            }
+        }
+    }
+
+    // G61, G61.1 and G64, the P and Q of G64 come from the rest of the block
+    fn set_path_mode(&mut self, word: &gcode::GCode, block: &gcode::GCodeBlock) {
+        let mode = if is_g(word, 61, 0) {
+            PathMode::ExactStop
+        } else if is_g(word, 61, 1) {
+            PathMode::ExactPath
+        } else if is_g(word, 64, 0) {
+            let value = |command| {
+                block
+                    .iter()
+                    .find(|parameter| parameter.command == command)
+                    .map(|parameter| parameter.major as f32 + parameter.minor)
             };
+            PathMode::Blending {
+                tolerance: value('P'),
+                naive_cam: value('Q'),
+            }
+        } else {
+            return;
+        };
+        self.message(format!("Path control mode: {:?}", mode));
+        self.path_mode = mode;
+    }
 
-            if step > movement_amplitude as i32 {
+    // Plane, units and distance mode. Only the XY plane, millimetres and absolute
+    // coordinates can be run, G18, G19, G20 and G91 are left unsupported.
+    fn set_coordinate_mode(&mut self, word: &gcode::GCode) {
+        if is_g(word, 17, 0) {
+            self.plane = Plane::XY;
+        } else if is_g(word, 21, 0) {
+            self.units = Units::Millimetres;
+        } else if is_g(word, 90, 0) {
+            self.distance_mode = DistanceMode::Absolute;
+        }
+    }
+
+    // Feed move following the executing block, if that block has nothing else
+    fn next_feed_move(&self, pc: i32, from: &ToolState) -> Option<ToolState> {
+        let block = self.program.get(&(pc + self.step))?;
+        match parse_move(block, from) {
+            Some((motion @ Motion::Linear { rapid: false }, target))
+                if block.iter().all(|word| {
+                    motion_word(word).is_some() || is_move_parameter(word, &motion)
+                }) =>
+            {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
+    // Extends the move to `next` over the following feed moves that stay within
+    // `tolerance` of a straight line, skipping the blocks merged into it
+    fn merge_collinear(&mut self, from: &ToolState, next: ToolState, tolerance: f32) -> ToolState {
+        let mut merged = next;
+        let mut passed: Vec<ToolState> = vec![];
+        while let Some(candidate) = self.next_feed_move(self.pc, &merged) {
+            if candidate.feedrate != merged.feedrate || candidate.e != merged.e {
+                break;
+            }
+            passed.push(merged.clone());
+            if passed
+                .iter()
+                .any(|point| distance_to_line(point, from, &candidate) > tolerance)
+            {
                 break;
             }
+            merged = candidate;
+            self.pc += self.step;
+            self.message(format!("Merged collinear move N{}", self.pc));
+        }
+        merged
+    }
+
+    fn corner_blend(
+        &self,
+        from: &ToolState,
+        corner: &ToolState,
+        tolerance: Option<f32>,
+    ) -> Option<Blend> {
+        let to = self.next_feed_move(self.pc, corner)?;
+        let resolution = 1.0 / self.toolconfig.steps_per_unit_x as f32;
+        let arc = corner_arc(from, corner, &to, tolerance, resolution)?;
+
+        let arc_length = arc
+            .points
+            .iter()
+            .fold((0.0, &arc.start), |(sum, previous), point| {
+                (sum + length(&xyz_delta(previous, point)), point)
+            })
+            .0;
+        let entry = [arc.incoming[0], arc.incoming[1], arc.incoming[2], 0.0];
+        let exit = [arc.outgoing[0], arc.outgoing[1], arc.outgoing[2], 0.0];
+        let axes = self.toolconfig.dynamics();
+        let dynamics =
+            AxisDynamics::along(&axes, entry).combined(&AxisDynamics::along(&axes, exit));
+        // Centripetal acceleration bounds the velocity through the blend
+        let centripetal = (dynamics.max_acceleration * arc.radius).sqrt();
+        let segment = planner::Segment::new(
+            arc_length,
+            entry,
+            exit,
+            self.feed(corner.feedrate).min(centripetal),
+            dynamics,
+        );
+
+        Some(Blend {
+            start: arc.start,
+            points: arc.points,
+            segment,
+        })
+    }
 
-            step += 1;
+    fn movement_interpolated(&mut self, next: ToolState, rapid: bool) -> bool {
+        self.message("Interpolated movement".to_string());
+        let mut current = self.toolstate.clone();
+
+        let (tolerance, naive_cam) = match self.path_mode {
+            PathMode::Blending {
+                tolerance,
+                naive_cam,
+            } if !rapid => (Some(tolerance), naive_cam),
+            _ => (None, None),
+        };
+        let next = match naive_cam {
+            Some(naive_cam) => self.merge_collinear(&current, next, naive_cam),
+            None => next,
+        };
+
+        if !self.check_soft_limits(&[('X', next.x), ('Y', next.y), ('Z', next.z)]) {
+            return false;
+        }
+
+        if current.feedrate != next.feedrate {
+            current.feedrate = next.feedrate;
+            self.add_to_queue(CommandEntry {
+                command: Command::Feedrate,
+                value: next.feedrate,
+                time: 0.0,
+            });
+        }
+
+        let blend = match tolerance {
+            Some(tolerance) => self.corner_blend(&current, &next, tolerance),
+            None => None,
+        };
+        match blend {
+            Some(blend) => {
+                let start = blend.start.clone();
+                let straight = self.segment(&current, &Motion::Linear { rapid }, &start);
+                let profile = self.plan_profile(vec![straight, blend.segment], &next);
+                let mut reported = (0.0, 0.0);
+                self.emit_line(
+                    &current,
+                    &start,
+                    &profile,
+                    0.0,
+                    profile.length,
+                    &mut reported,
+                );
+                self.finish_profile(&profile, &reported);
+
+                // Follow the rounded corner chord by chord
+                let profile = self.plan_profile(vec![blend.segment], &next);
+                let mut reported = (0.0, 0.0);
+                let mut distance = 0.0;
+                let mut previous = start;
+                for point in blend.points.iter() {
+                    let chord = length(&xyz_delta(&previous, point));
+                    self.emit_line(&previous, point, &profile, distance, chord, &mut reported);
+                    distance += chord;
+                    previous = point.clone();
+                }
+                self.finish_profile(&profile, &reported);
+            }
+            None => {
+                let segment = self.segment(&current, &Motion::Linear { rapid }, &next);
+                let profile = self.plan_profile(vec![segment], &next);
+                let mut reported = (0.0, 0.0);
+                self.emit_line(
+                    &current,
+                    &next,
+                    &profile,
+                    0.0,
+                    profile.length,
+                    &mut reported,
+                );
+                self.finish_profile(&profile, &reported);
+            }
         }
 
         self.add_to_queue(CommandEntry {
             command: Command::Done,
             value: 0.0,
+            time: 0.0,
         });
 
-        return true;
+        true
     }
 
-    fn movement_arc(&self, parameters: &gcode::GCodeBlock, clockwise: bool) -> bool {
-        println!("Arc movement");
-        let mut next = self.toolstate.clone();
-        let mut center: (f32, f32) = (0.0, 0.0);
-        for parameter in parameters.iter().skip(1) {
-            match parameter.command {
-                'X' => next.x = parameter.major as f32 + parameter.minor,
-                'Y' => next.y = parameter.major as f32 + parameter.minor,
-                'I' => center.0 = parameter.major as f32 + parameter.minor,
-                'J' => center.1 = parameter.major as f32 + parameter.minor,
-                'E' => next.e = parameter.major as f32 + parameter.minor,
-                'F' => next.feedrate = parameter.major as f32 + parameter.minor,
-                _ => println!("Unsupported parameter, {:?}", parameter),
-            }
-        }
+    fn movement_arc(&mut self, next: ToolState, center: (f32, f32), clockwise: bool) -> bool {
+        self.message("Arc movement".to_string());
+        let current = self.toolstate.clone();
 
-        let mut current = self.toolstate.clone();
+        let (radius, start_angle, stop_angle) = arc_sweep(&current, &next, center, clockwise);
 
-        let (radius, start_angle, raw_stop_angle) = calculate_angles(
-            Position::new(current.x, current.y),
-            Position::new(next.x, next.y),
-            Position::new(center.0, center.1),
+        let (min, max) = arc_extents(
+            (current.x + center.0, current.y + center.1),
+            radius,
+            start_angle,
+            stop_angle,
         );
-        let stop_angle = if start_angle > raw_stop_angle {
-            if clockwise {
-                raw_stop_angle
-            } else {
-                2.0 * PI + raw_stop_angle
-            }
-        } else {
-            if clockwise {
-                raw_stop_angle - 2.0 * PI
-            } else {
-                raw_stop_angle
-            }
-        };
+        if !self.check_soft_limits(&[('X', min.0), ('X', max.0), ('Y', min.1), ('Y', max.1)]) {
+            return false;
+        }
 
         let mut angle = FixedResolution::new(
             start_angle,
@@ -621,50 +2241,53 @@ impl SimpleMachine {
         let start_y = FixedResolution::new(current.y, self.toolconfig.steps_per_unit_y);
         let center_x = FixedResolution::new(current.x + center.0, self.toolconfig.steps_per_unit_x);
         let center_y = FixedResolution::new(current.y + center.1, self.toolconfig.steps_per_unit_y);
-        let mut current_x = start_x.clone();
-        let mut current_y = start_y.clone();
+        let mut current_x = start_x;
+        let mut current_y = start_y;
         let stop_x = FixedResolution::new(next.x, self.toolconfig.steps_per_unit_x);
         let stop_y = FixedResolution::new(next.y, self.toolconfig.steps_per_unit_y);
 
         if current.feedrate != next.feedrate {
-            current.feedrate = next.feedrate;
             self.add_to_queue(CommandEntry {
                 command: Command::Feedrate,
                 value: next.feedrate,
+                time: 0.0,
             });
         }
 
+        let segment = self.segment(&current, &Motion::Arc { clockwise, center }, &next);
+        let profile = self.plan_profile(vec![segment], &next);
+        let mut reported = (0.0, 0.0);
+
         loop {
             match angle.get_direction(&stop) {
                 Some(direction) => {
                     angle = angle.increment(direction);
+                    let swept = (angle.repr() - start_angle) / (stop_angle - start_angle);
+                    self.follow_profile(&profile, swept * profile.length, &mut reported);
+                    let time = self.step_time(&profile, swept * profile.length, &reported);
                     let cartesian = ((radius * angle.repr().cos()), (radius * angle.repr().sin()));
-                    let x = (center_x.repr() + cartesian.0);
-                    let y = (center_y.repr() + cartesian.1);
+                    let x = center_x.repr() + cartesian.0;
+                    let y = center_y.repr() + cartesian.1;
 
                     let next_x = FixedResolution::new(x, self.toolconfig.steps_per_unit_x);
                     let next_y = FixedResolution::new(y, self.toolconfig.steps_per_unit_y);
 
-                    match current_x.get_direction(&next_x) {
-                        Some(direction) => {
-                            current_x = current_x.increment(direction);
-                            self.add_to_queue(CommandEntry {
-                                command: Command::StepperX,
-                                value: direction as f32,
-                            });
-                        }
-                        None => {}
+                    if let Some(direction) = current_x.get_direction(&next_x) {
+                        current_x = current_x.increment(direction);
+                        self.add_to_queue(CommandEntry {
+                            command: Command::StepperX,
+                            value: direction as f32,
+                            time,
+                        });
                     };
 
-                    match current_y.get_direction(&next_y) {
-                        Some(direction) => {
-                            current_y = current_y.increment(direction);
-                            self.add_to_queue(CommandEntry {
-                                command: Command::StepperY,
-                                value: direction as f32,
-                            });
-                        }
-                        None => {}
+                    if let Some(direction) = current_y.get_direction(&next_y) {
+                        current_y = current_y.increment(direction);
+                        self.add_to_queue(CommandEntry {
+                            command: Command::StepperY,
+                            value: direction as f32,
+                            time,
+                        });
                     };
 
                     if current_x.equal(&stop_x) && current_y.equal(&stop_y) {
@@ -687,16 +2310,611 @@ impl SimpleMachine {
             }
         }
 
+        self.finish_profile(&profile, &reported);
         self.add_to_queue(CommandEntry {
             command: Command::Done,
             value: 0.0,
+            time: 0.0,
         });
 
-        return true;
+        true
     }
 
-    fn add_to_queue(&self, entry: CommandEntry) -> i32 {
-        self.queue.send(entry).expect("Sent failed!");
+    fn add_to_queue(&mut self, entry: CommandEntry) -> i32 {
+        if let Some(sync) = self.stepper.handle(&entry) {
+            self.sync = Some(sync);
+        }
+
+        match entry.command {
+            Command::StepperX | Command::StepperY | Command::StepperZ | Command::StepperE => {
+                let velocity = self.stepper.syncentry.velocity;
+                self.history
+                    .record_motion(entry.time, self.pc, self.position(), velocity);
+                let axis = entry.command.axis().unwrap_or(0);
+                let direction = if entry.value < 0.0 { -1 } else { 1 };
+                for sink in self.sinks.iter_mut() {
+                    sink.step(axis, direction, entry.time);
+                }
+            }
+            Command::Velocity => {
+                let time = self.toolstate.time + self.stepper.syncentry.elapsed;
+                for sink in self.sinks.iter_mut() {
+                    sink.progress(entry.value, time);
+                }
+            }
+            _ => (),
+        }
         1
     }
+
+    // Tool position including the steps of the executing move
+    fn position(&self) -> [f32; 4] {
+        let syncentry = &self.stepper.syncentry;
+        let moved = [
+            syncentry.steps_x,
+            syncentry.steps_y,
+            syncentry.steps_z,
+            syncentry.steps_e,
+        ];
+        let mut steps = self.steps;
+        for (steps, moved) in steps.iter_mut().zip(moved.iter()) {
+            *steps += *moved as i64;
+        }
+        self.steps_to_units(&steps)
+    }
+
+    fn message(&mut self, message: String) {
+        for sink in self.sinks.iter_mut() {
+            sink.message(self.pc, &message);
+        }
+    }
+
+    fn error(&mut self, error: MachineError) {
+        for sink in self.sinks.iter_mut() {
+            sink.error(self.pc, &error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, Recorder};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_calculate_units() {
+        {
+            let (_, start_angle, stop_angle) = calculate_angles(
+                Position { x: 10.0, y: 10.0 },
+                Position { x: 20.0, y: 20.0 },
+                Position { x: 0.0, y: 10.0 },
+            );
+
+            let result = calculate_units(start_angle, stop_angle);
+
+            assert_eq!(Position { x: 0.0, y: -1.0 }, result.0);
+            assert_eq!(Position { x: 1.0, y: 0.0 }, result.1);
+        }
+        {
+            let (_, start_angle, stop_angle) = calculate_angles(
+                Position { x: 30.0, y: 30.0 },
+                Position { x: 40.0, y: 40.0 },
+                Position { x: 10.0, y: 0.0 },
+            );
+
+            let result = calculate_units(start_angle, stop_angle);
+
+            assert_eq!(Position { x: -1.0, y: 0.0 }, result.0);
+            assert_eq!(Position { x: 0.0, y: 1.0 }, result.1);
+        }
+    }
+
+    #[test]
+    fn test_calculate_angles() {
+        {
+            let (radius, start_angle, stop_angle) = calculate_angles(
+                Position { x: 10.0, y: 10.0 },
+                Position { x: 20.0, y: 20.0 },
+                Position { x: 0.0, y: 10.0 },
+            );
+
+            assert_eq!(-0.5 * PI, start_angle);
+            assert_eq!(0.0, stop_angle);
+            assert_eq!(10.0, radius);
+        }
+        {
+            let (radius, start_angle, stop_angle) = calculate_angles(
+                Position { x: 30.0, y: 30.0 },
+                Position { x: 40.0, y: 40.0 },
+                Position { x: 10.0, y: 0.0 },
+            );
+
+            assert_eq!(-PI, start_angle);
+            assert_eq!(0.5 * PI, stop_angle);
+            assert_eq!(10.0, radius);
+        }
+    }
+
+    #[test]
+    fn test_travel_limits() {
+        let limits = TravelLimits::new(-10.0, 20.0);
+
+        assert_eq!(None, limits.overshoot(0.0));
+        assert_eq!(None, limits.overshoot(20.0));
+        assert_eq!(Some(5.0), limits.overshoot(25.0));
+        assert_eq!(Some(-2.0), limits.overshoot(-12.0));
+
+        assert_eq!(Some(limits), TravelLimits::parse("-10:20"));
+        assert_eq!(None, TravelLimits::parse("20:-10"));
+        assert_eq!(None, TravelLimits::parse("-10"));
+        assert_eq!(None, TravelLimits::unlimited().overshoot(1e30));
+    }
+
+    fn point(x: f32, y: f32) -> ToolState {
+        let mut state = ToolState::new();
+        state.x = x;
+        state.y = y;
+        state
+    }
+
+    #[test]
+    fn test_distance_to_line() {
+        let from = point(0.0, 0.0);
+        let to = point(10.0, 0.0);
+
+        assert_eq!(2.0, distance_to_line(&point(5.0, 2.0), &from, &to));
+        assert_eq!(5.0, distance_to_line(&point(-3.0, 4.0), &from, &to));
+    }
+
+    #[test]
+    fn test_corner_arc() {
+        let from = point(0.0, 0.0);
+        let corner = point(10.0, 0.0);
+        let to = point(10.0, 10.0);
+
+        // Straight and reversing corners cannot be rounded off
+        assert!(corner_arc(&from, &corner, &point(20.0, 0.0), None, 0.01).is_none());
+        assert!(corner_arc(&from, &corner, &point(5.0, 0.0), None, 0.01).is_none());
+
+        // Without tolerance the blend takes up half of the shorter move
+        let arc = corner_arc(&from, &corner, &to, None, 0.01).unwrap();
+        assert!((arc.start.x - 5.0).abs() < 1e-4 && arc.start.y.abs() < 1e-4);
+        assert!((arc.radius - 5.0).abs() < 1e-4);
+        let end = arc.points.last().unwrap();
+        assert!((end.x - 10.0).abs() < 1e-3 && (end.y - 5.0).abs() < 1e-3);
+        for point in arc.points.iter() {
+            let radius = ((point.x - 5.0).powi(2) + (point.y - 5.0).powi(2)).sqrt();
+            assert!((radius - 5.0).abs() < 1e-3);
+        }
+
+        // The arc midpoint stays within tolerance of the corner
+        let arc = corner_arc(&from, &corner, &to, Some(0.1), 0.001).unwrap();
+        let center = (10.0 - arc.radius, arc.radius);
+        let apex = (
+            center.0 + arc.radius * 0.5_f32.sqrt(),
+            center.1 - arc.radius * 0.5_f32.sqrt(),
+        );
+        let deviation = ((apex.0 - 10.0).powi(2) + apex.1.powi(2)).sqrt();
+        assert!((deviation - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_arc_extents() {
+        {
+            // Quarter circle from -90 to 0 degrees stays within its end points
+            let (min, max) = arc_extents((10.0, 20.0), 10.0, -0.5 * PI, 0.0);
+
+            assert!((min.0 - 10.0).abs() < 1e-4 && (min.1 - 10.0).abs() < 1e-4);
+            assert!((max.0 - 20.0).abs() < 1e-4 && (max.1 - 20.0).abs() < 1e-4);
+        }
+        {
+            // Counterclockwise half circle from -180 to 0 degrees passes through -90
+            let (min, max) = arc_extents((0.0, 0.0), 5.0, -PI, 0.0);
+
+            assert!((min.0 + 5.0).abs() < 1e-4 && (min.1 + 5.0).abs() < 1e-4);
+            assert!((max.0 - 5.0).abs() < 1e-4 && max.1.abs() < 1e-4);
+        }
+        {
+            // Clockwise from 0 to -270 degrees sweeps through -90 and -180
+            let (min, max) = arc_extents((0.0, 0.0), 5.0, 0.0, -1.5 * PI);
+
+            assert!((min.0 + 5.0).abs() < 1e-4 && (min.1 + 5.0).abs() < 1e-4);
+            assert!((max.0 - 5.0).abs() < 1e-4 && (max.1 - 5.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_synchronous_run() {
+        let program = "G1 X10 Y5 F600\nG4 P0.5\nG0 X-20 Y0\nG1 X200\nG1 X0\n";
+        let mut toolconfig = ToolConfig::new();
+        toolconfig.travel_x = TravelLimits::new(-150.0, 150.0);
+        toolconfig.halt_on_soft_limit = true;
+        let mut machine = SimpleMachine::new(toolconfig.clone());
+        machine.load_str(program);
+        assert_eq!(MachineStatus::Running, machine.status());
+
+        assert_eq!(MachineStatus::Running, machine.step_block());
+        assert_eq!(1, machine.current_block());
+        assert_eq!(10.0, machine.toolstate().x);
+        assert_eq!(5.0, machine.toolstate().y);
+
+        // Halts on the move past the X travel, the dwell is in the cycle time
+        assert_eq!(MachineStatus::Halted, machine.run_to_end());
+        assert_eq!(3, machine.current_block());
+        assert_eq!(-20.0, machine.toolstate().x);
+        assert_eq!(0.0, machine.toolstate().y);
+        assert!((machine.toolstate().time - machine.cycle_time().total()).abs() < 1e-3);
+        assert!(machine.cycle_time().blocks[1].dwell == 0.5);
+        assert_eq!(1, machine.soft_limit_violations().len());
+
+        // Without halting the violation is only reported
+        toolconfig.halt_on_soft_limit = false;
+        machine.set_toolconfig(toolconfig);
+        machine.load_str(program);
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+        assert_eq!(1, machine.soft_limit_violations().len());
+
+        // Loading again starts over
+        machine.load_str("G1 X1\n");
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+        assert_eq!(1.0, machine.toolstate().x);
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        use crate::breakpoints::StateField;
+
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G1 X10 F600\nG1 Z-2\nT3 M6\nG1 Z-3\nG1 Z5\nG1 Z-4\n");
+        let deep = machine.add_breakpoint(Condition::Below(StateField::Z, -1.0));
+        let change = machine.add_breakpoint(Condition::Word('M', Some(6)));
+
+        // Watchpoints stop after the block that crossed the threshold
+        assert_eq!(MachineStatus::Break(deep), machine.run_to_end());
+        assert_eq!(2, machine.current_block());
+        assert_eq!(-2.0, machine.toolstate().z);
+
+        // Breakpoints stop before the block, which runs when resumed
+        assert_eq!(MachineStatus::Break(change), machine.run_to_end());
+        assert_eq!(0, machine.toolstate().tool);
+        assert_eq!(MachineStatus::Running, machine.step_block());
+        assert_eq!(3, machine.toolstate().tool);
+
+        // Staying below does not fire again, dropping below once more does
+        assert_eq!(MachineStatus::Break(deep), machine.run_to_end());
+        assert_eq!(-4.0, machine.toolstate().z);
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+
+        assert!(machine.remove_breakpoint(deep));
+        assert!(!machine.remove_breakpoint(deep));
+        assert_eq!(1, machine.breakpoints().len());
+    }
+
+    #[test]
+    fn test_program_bounds() {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G0 X10 Y-5 Z2\nG1 Z-1\nG2 X20 Y-5 I5 J0\nG0 X0 Y0\n");
+        let bounds = machine.program_bounds();
+
+        // The clockwise arc bulges up to Y0
+        let expected = [[0.0, -5.0, -1.0], [20.0, 0.0, 2.0], [20.0, 5.0, 3.0]];
+        for (actual, expected) in [bounds.min, bounds.max, bounds.size()]
+            .iter()
+            .zip(&expected)
+        {
+            for axis in 0..3 {
+                assert!((actual[axis] - expected[axis]).abs() < 1e-4);
+            }
+        }
+        assert_eq!(MachineStatus::Running, machine.status());
+    }
+
+    #[test]
+    fn test_toolpath() {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G0 X10 Y-5 Z2\nG0 Z1\nG1 Z-1 F100\nG3 X20 Y-5 I5 J0\nG1 X30\nG0 Z5\n");
+        let path = machine.toolpath();
+        let kinds: Vec<MoveKind> = path.iter().map(|segment| segment.kind).collect();
+        assert_eq!(
+            vec![
+                MoveKind::Rapid,
+                MoveKind::Plunge,
+                MoveKind::Plunge,
+                MoveKind::Arc,
+                MoveKind::Feed,
+                MoveKind::Retract
+            ],
+            kinds
+        );
+
+        // Half a circle below the centre in 5 degree chords
+        let arc = &path[3];
+        assert_eq!(3, arc.block);
+        assert_eq!(37, arc.points.len());
+        assert_eq!([10.0, -5.0, -1.0], arc.points[0]);
+        let bottom = arc.points[18];
+        assert!((bottom[0] - 15.0).abs() < 1e-4 && (bottom[1] + 10.0).abs() < 1e-4);
+        assert_eq!(vec![[20.0, -5.0, -1.0], [30.0, -5.0, -1.0]], path[4].points);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let program = "S1000\nG1 X20 F600\nG0 X100\n";
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str(program);
+        machine.run_to_end();
+        let full = machine.cycle_time().blocks.clone();
+
+        // Half feed doubles the feed move, the rapid only starts slower
+        machine.set_overrides(Overrides {
+            feed: 50.0,
+            rapid: 100.0,
+            spindle: 80.0,
+        });
+        machine.load_str(program);
+        machine.step_block();
+        assert_eq!(800.0, machine.effective_spindle_speed());
+        machine.run_to_end();
+        assert_eq!(600.0, machine.toolstate().feedrate);
+        assert_eq!(300.0, machine.effective_feedrate());
+        let half = &machine.cycle_time().blocks;
+        assert!((half[0].motion - 2.0 * full[0].motion).abs() < 0.1);
+        assert!((half[1].motion - full[1].motion).abs() < 0.05);
+
+        // Rapids slow down with their own override only, within its range
+        machine.set_overrides(Overrides {
+            feed: 100.0,
+            rapid: 1.0,
+            spindle: 100.0,
+        });
+        assert_eq!(5.0, machine.overrides().rapid);
+        machine.load_str(program);
+        machine.run_to_end();
+        let slow = &machine.cycle_time().blocks;
+        assert!((slow[0].motion - full[0].motion).abs() < 0.05);
+        assert!(slow[1].motion > 10.0 * full[1].motion);
+    }
+
+    #[test]
+    fn test_step_back_and_jump() {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G1 X10 F600\nT2\nM6\nG1 X20 Z-1\nG1 Y5\n");
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+        let finished = machine.toolstate().clone();
+        let segments = machine.planned_segments().len();
+        let samples = machine.history().motion().len();
+
+        // Back before the tool change, T2 already selected
+        machine.step_back();
+        machine.step_back();
+        assert_eq!(MachineStatus::Running, machine.step_back());
+        assert_eq!(2, machine.current_block());
+        assert_eq!(0, machine.toolstate().tool);
+        assert_eq!(2, machine.modal_state().selected_tool);
+        assert_eq!(10.0, machine.toolstate().x);
+
+        // Forward again without executing anything
+        machine.jump_to_block(4);
+        assert_eq!(4, machine.current_block());
+        assert_eq!(-1.0, machine.toolstate().z);
+        assert_eq!(samples, machine.history().motion().len());
+
+        // Halfway through the first move is still its start
+        machine.jump_to_time(0.5);
+        assert_eq!(0, machine.current_block());
+        assert_eq!(0.0, machine.toolstate().x);
+
+        // Running on records the same run once more
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+        assert_eq!(finished, *machine.toolstate());
+        assert_eq!(segments, machine.planned_segments().len());
+        assert_eq!(samples, machine.history().motion().len());
+        assert!((finished.time - machine.cycle_time().total()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_spindle_and_coolant() {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G64 P0.1\nS1000 M3\nM8\nG1 X10 F600\nM5 M9\n");
+        for _ in 0..3 {
+            machine.step_block();
+        }
+        let modal = machine.modal_state();
+        assert_eq!(Spindle::Clockwise, modal.spindle);
+        assert_eq!(Coolant::Flood, modal.coolant);
+        assert_eq!(
+            "G64 P0.1 M3 M8",
+            format!("{} {} {}", modal.path_mode, modal.spindle, modal.coolant)
+        );
+        assert_eq!(MotionMode::Rapid, modal.motion_mode);
+
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+        assert_eq!(Spindle::Off, machine.modal_state().spindle);
+        assert_eq!(Coolant::Off, machine.modal_state().coolant);
+
+        // Both come back with the block they were on for
+        machine.jump_to_block(3);
+        assert_eq!(Spindle::Clockwise, machine.modal_state().spindle);
+        assert_eq!(Coolant::Flood, machine.modal_state().coolant);
+        assert_eq!("G61.1", PathMode::ExactPath.to_string());
+    }
+
+    #[test]
+    fn test_modal_codes() {
+        let recorder = Rc::new(RefCell::new(Recorder::new(false)));
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G17\nG21\nG90\nG91\nG20\nG2 X10 I5 Z1 F600\n");
+        machine.add_sink(Box::new(recorder.clone()));
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+
+        // Only the codes the machine runs are taken, the others reported, as is
+        // a Z on an arc
+        let modal = machine.modal_state();
+        assert_eq!(
+            "G2 G17 G21 G90",
+            format!(
+                "{} {} {} {}",
+                modal.motion_mode, modal.plane, modal.units, modal.distance_mode
+            )
+        );
+        let unsupported: Vec<i32> = recorder
+            .borrow()
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Error(block, MachineError::Unsupported(_)) => Some(*block),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![3, 4, 5], unsupported);
+
+        machine.jump_to_block(5);
+        assert_eq!(MotionMode::Rapid, machine.modal_state().motion_mode);
+    }
+
+    #[test]
+    fn test_mixed_blocks() {
+        let recorder = Rc::new(RefCell::new(Recorder::new(false)));
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G90 G54 G0 X7 Y7 S1200 M3 T2\nG21 G1 X5 F100 M8\nM6 G1 Y5\n");
+        machine.add_sink(Box::new(recorder.clone()));
+
+        // The modal words and the move both run, only G54 is left over
+        assert_eq!(MachineStatus::Running, machine.step_block());
+        let state = machine.toolstate();
+        assert_eq!((7.0, 7.0), (state.x, state.y));
+        let modal = machine.modal_state();
+        assert_eq!(Spindle::Clockwise, modal.spindle);
+        assert_eq!(1200.0, modal.spindle_speed);
+        assert_eq!(2, modal.selected_tool);
+        assert_eq!(MotionMode::Rapid, modal.motion_mode);
+
+        assert_eq!(MachineStatus::Running, machine.step_block());
+        assert_eq!(5.0, machine.toolstate().x);
+        assert_eq!(Coolant::Flood, machine.modal_state().coolant);
+
+        // The tool change comes before the move of its block
+        assert_eq!(MachineStatus::Finished, machine.step_block());
+        let state = machine.toolstate();
+        assert_eq!((2, 5.0), (state.tool, state.y));
+
+        let unsupported: Vec<(i32, gcode::GCodeBlock)> = recorder
+            .borrow()
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Error(block, MachineError::Unsupported(words)) => {
+                    Some((*block, words.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(1, unsupported.len());
+        assert_eq!(0, unsupported[0].0);
+        assert_eq!(
+            ('G', 54),
+            (unsupported[0].1[0].command, unsupported[0].1[0].major)
+        );
+    }
+
+    #[test]
+    fn test_random_moves_reach_targets() {
+        // Two decimal targets land on their step, however many moves came before
+        let mut seed: u64 = 7;
+        let mut random = || {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((seed >> 33) % 10_001) as f32 / 100.0 - 50.0
+        };
+        let targets: Vec<(f32, f32)> = (0..100).map(|_| (random(), random())).collect();
+        let program: String = targets
+            .iter()
+            .map(|(x, y)| format!("G1 X{:.2} Y{:.2} F6000\n", x, y))
+            .collect();
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G1 X-67.64 Y18.05 F6000\n");
+        machine.run_to_end();
+        assert_eq!(
+            (-67.64, 18.05),
+            (machine.toolstate().x, machine.toolstate().y)
+        );
+
+        machine.load_str(&program);
+        for (x, y) in targets.iter() {
+            machine.step_block();
+            let state = machine.toolstate();
+            assert_eq!(to_fixed(*x, 100), to_fixed(state.x, 100));
+            assert_eq!(to_fixed(*y, 100), to_fixed(state.y, 100));
+        }
+        assert_eq!(MachineStatus::Finished, machine.status());
+    }
+
+    #[test]
+    fn test_program_end() {
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("S1000 M3\nG1 X10 F600\nM30\nG1 X20\n");
+        assert_eq!(1, machine.toolpath().len());
+        assert_eq!(10.0, machine.program_bounds().max[0]);
+
+        // Nothing after M30 runs, the spindle is stopped
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+        assert_eq!(10.0, machine.toolstate().x);
+        assert_eq!(3, machine.current_block());
+        assert_eq!(Spindle::Off, machine.modal_state().spindle);
+
+        // Until stepping back before it
+        assert_eq!(MachineStatus::Running, machine.step_back());
+        assert_eq!(Spindle::Clockwise, machine.modal_state().spindle);
+        assert_eq!(MachineStatus::Finished, machine.step_block());
+
+        // At the end of a move the same, the machine and the toolpath agree
+        let recorder = Rc::new(RefCell::new(Recorder::new(false)));
+        machine.load_str("G1 X5 F100 M30\nG1 X10\n");
+        machine.add_sink(Box::new(recorder.clone()));
+        assert_eq!(1, machine.toolpath().len());
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+        assert_eq!(5.0, machine.toolstate().x);
+        assert!(!recorder
+            .borrow()
+            .events
+            .iter()
+            .any(|event| matches!(event, Event::Error(..))));
+    }
+
+    #[test]
+    fn test_examples() {
+        // Every example runs to its end, whatever it does not support
+        for file in std::fs::read_dir("examples").unwrap() {
+            let path = file.unwrap().path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("gcode") {
+                continue;
+            }
+            let mut machine = SimpleMachine::new(ToolConfig::new());
+            machine.load_file(path.to_str().unwrap()).unwrap();
+            assert_eq!(MachineStatus::Finished, machine.run_to_end(), "{:?}", path);
+        }
+
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        let mut program = gcode::parse_str("G1 X10 F600\n");
+        program.insert(1, vec![]);
+        machine.load(program);
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+    }
+
+    #[test]
+    fn test_blended_step_times() {
+        // The rounded corners run as profiles of their own after the straight part
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G64 P0.5\nG1 X20 F1200\nG1 Y20\nG1 X0\nG1 Y0\n");
+        assert_eq!(MachineStatus::Finished, machine.run_to_end());
+
+        let motion = machine.history().motion();
+        assert!(motion.windows(2).all(|pair| pair[0].time <= pair[1].time));
+        assert_eq!([0; 4], machine.step_rates().violations);
+        let end = motion.last().unwrap().time as f32;
+        assert!((end - machine.toolstate().time).abs() < 1e-3);
+    }
 }
@@ -0,0 +1,201 @@
+// Integer DDA (Bresenham) step generator over the four machine axes.
+// Every tick moves the dominant axis one step, the other axes step whenever
+// their error term overflows, so the step count per axis is always exact.
+#[derive(Debug, Clone)]
+pub struct StepGenerator {
+    steps: [i64; 4],
+    direction: [i32; 4],
+    error: [i64; 4],
+    ticks: i64,
+    tick: i64,
+}
+
+impl StepGenerator {
+    // Delta per axis in steps, signed
+    pub fn new(delta: [i64; 4]) -> Self {
+        let mut steps = [0; 4];
+        let mut direction = [0; 4];
+        for axis in 0..4 {
+            steps[axis] = delta[axis].abs();
+            direction[axis] = delta[axis].signum() as i32;
+        }
+        let ticks = steps.iter().cloned().max().unwrap_or(0);
+
+        // Start half way so the rounding is symmetric along the move
+        StepGenerator {
+            steps,
+            direction,
+            error: [ticks / 2; 4],
+            ticks,
+            tick: 0,
+        }
+    }
+
+    // Number of ticks, equal to the steps of the dominant axis
+    pub fn ticks(&self) -> i64 {
+        self.ticks
+    }
+}
+
+impl Iterator for StepGenerator {
+    // Direction of the step taken per axis this tick, 0 when the axis stands still
+    type Item = [i32; 4];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.tick >= self.ticks {
+            return None;
+        }
+        self.tick += 1;
+
+        let mut step = [0; 4];
+        for (axis, error) in self.error.iter_mut().enumerate() {
+            *error += self.steps[axis];
+            if *error >= self.ticks {
+                *error -= self.ticks;
+                step[axis] = self.direction[axis];
+            }
+        }
+        Some(step)
+    }
+}
+
+// Step frequency per axis as seen by the stepper stage, from the time
+// between consecutive steps of the same move
+#[derive(Debug, Clone)]
+pub struct StepRateMonitor {
+    limits: [f32; 4],
+    last_step: [Option<f64>; 4],
+    pub max_rate: [f32; 4],
+    pub violations: [usize; 4],
+}
+
+impl StepRateMonitor {
+    // Max step frequency per axis, Hz
+    pub fn new(limits: [f32; 4]) -> Self {
+        StepRateMonitor {
+            limits,
+            last_step: [None; 4],
+            max_rate: [0.0; 4],
+            violations: [0; 4],
+        }
+    }
+
+    pub fn limits(&self) -> [f32; 4] {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: [f32; 4]) {
+        self.limits = limits;
+    }
+
+    // Step on `axis` at `time` seconds since program start, returns the rate
+    // relative to the previous step on that axis
+    pub fn step(&mut self, axis: usize, time: f64) -> Option<f32> {
+        let previous = self.last_step[axis].replace(time)?;
+        let interval = time - previous;
+        let rate = if interval > 0.0 {
+            (1.0 / interval) as f32
+        } else {
+            f32::INFINITY
+        };
+
+        if rate > self.max_rate[axis] {
+            self.max_rate[axis] = rate;
+        }
+        if rate > self.limits[axis] {
+            self.violations[axis] += 1;
+        }
+        Some(rate)
+    }
+
+    // Moves start from their own first step
+    pub fn end_move(&mut self) {
+        self.last_step = [None; 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small deterministic generator, the same moves on every platform
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn range(&mut self, limit: i64) -> i64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((self.0 >> 33) as i64 % (2 * limit + 1)) - limit
+        }
+    }
+
+    #[test]
+    fn test_exact_steps() {
+        let generator = StepGenerator::new([300, -100, 0, 7]);
+        assert_eq!(300, generator.ticks());
+
+        let mut position = [0; 4];
+        for step in generator {
+            // Dominant axis steps every tick
+            assert_eq!(1, step[0]);
+            for axis in 0..4 {
+                position[axis] += step[axis] as i64;
+            }
+        }
+        assert_eq!([300, -100, 0, 7], position);
+        assert_eq!(0, StepGenerator::new([0; 4]).count());
+    }
+
+    #[test]
+    fn test_random_moves() {
+        let mut random = Lcg(0x5eed);
+        let mut position = [0i64; 4];
+
+        for _ in 0..5000 {
+            let target = [
+                random.range(3000),
+                random.range(3000),
+                random.range(1000),
+                random.range(5000),
+            ];
+            let mut delta = [0; 4];
+            for axis in 0..4 {
+                delta[axis] = target[axis] - position[axis];
+            }
+
+            let start = position;
+            let generator = StepGenerator::new(delta);
+            let ticks = generator.ticks();
+            for (tick, step) in generator.enumerate() {
+                for axis in 0..4 {
+                    position[axis] += step[axis] as i64;
+                    // Never more than one step away from the ideal line
+                    let ideal = delta[axis] * (tick as i64 + 1);
+                    let actual = (position[axis] - start[axis]) * ticks;
+                    assert!((ideal - actual).abs() <= ticks);
+                }
+            }
+            assert_eq!(target, position);
+        }
+    }
+
+    #[test]
+    fn test_step_rate() {
+        let mut monitor = StepRateMonitor::new([5000.0, 5000.0, 1000.0, 1000.0]);
+        assert_eq!(None, monitor.step(0, 0.0));
+        assert_eq!(Some(4000.0), monitor.step(0, 0.00025));
+        assert_eq!(0, monitor.violations[0]);
+
+        // 10 kHz on X is over the limit
+        monitor.step(0, 0.00035);
+        assert_eq!(1, monitor.violations[0]);
+        assert!((monitor.max_rate[0] - 10000.0).abs() < 1.0);
+
+        // Intervals are not measured across moves
+        monitor.end_move();
+        assert_eq!(None, monitor.step(0, 0.00036));
+        assert_eq!(None, monitor.step(2, 0.00036));
+    }
+}
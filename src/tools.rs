@@ -0,0 +1,239 @@
+use std::f32::consts::PI;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ToolShape {
+    FlatEndMill,
+    BallEndMill,
+    Drill,
+    Chamfer,
+    VBit,
+}
+
+impl ToolShape {
+    fn parse(name: &str) -> Option<ToolShape> {
+        match name.to_lowercase().as_str() {
+            "flat" => Some(ToolShape::FlatEndMill),
+            "ball" => Some(ToolShape::BallEndMill),
+            "drill" => Some(ToolShape::Drill),
+            "chamfer" => Some(ToolShape::Chamfer),
+            "vbit" => Some(ToolShape::VBit),
+            _ => None,
+        }
+    }
+
+    // Included angle of the tip, degrees
+    fn default_angle(self) -> f32 {
+        match self {
+            ToolShape::Drill => 118.0,
+            ToolShape::Chamfer => 90.0,
+            ToolShape::VBit => 60.0,
+            ToolShape::FlatEndMill | ToolShape::BallEndMill => 180.0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Tool {
+    pub number: i32,
+    pub shape: ToolShape,
+    pub diameter: f32,
+    // Cutting length up from the tip
+    pub length: f32,
+    // Included angle of a pointed tip, degrees
+    pub angle: f32,
+}
+
+impl Tool {
+    // "T1 flat D6 L20", "T3 drill D5" or "T5 vbit D6 A30". The length is three
+    // diameters and the angle that of the shape unless given. Diameter and
+    // length have to be positive and a given angle between 0 and 180 degrees.
+    pub fn parse(text: &str) -> Option<Tool> {
+        let mut words = text.split_whitespace();
+        let number = words.next()?.strip_prefix(['T', 't'])?.parse().ok()?;
+        let shape = ToolShape::parse(words.next()?)?;
+        let mut diameter = None;
+        let mut length = None;
+        let mut angle = shape.default_angle();
+        for word in words {
+            let mut chars = word.chars();
+            let command = chars.next()?.to_ascii_uppercase();
+            let value: f32 = chars.as_str().parse().ok()?;
+            match command {
+                'D' if value > 0.0 => diameter = Some(value),
+                'L' if value > 0.0 => length = Some(value),
+                'A' if value > 0.0 && value < 180.0 => angle = value,
+                _ => return None,
+            }
+        }
+        let diameter = diameter?;
+        Some(Tool {
+            number,
+            shape,
+            diameter,
+            length: length.unwrap_or(3.0 * diameter),
+            angle,
+        })
+    }
+
+    // Outline of one side as radius and height above the tip, from the tip up
+    pub fn profile(&self) -> Vec<[f32; 2]> {
+        let radius = 0.5 * self.diameter;
+        let mut profile = vec![[0.0, 0.0]];
+        match self.shape {
+            ToolShape::FlatEndMill => profile.push([radius, 0.0]),
+            ToolShape::BallEndMill => {
+                for step in 1..=8 {
+                    let angle = step as f32 / 8.0 * 0.5 * PI;
+                    profile.push([radius * angle.sin(), radius * (1.0 - angle.cos())]);
+                }
+            }
+            ToolShape::Drill | ToolShape::Chamfer | ToolShape::VBit => {
+                let half_angle = (0.5 * self.angle).to_radians();
+                profile.push([radius, radius / half_angle.tan()]);
+            }
+        }
+        if self.length > profile[profile.len() - 1][1] {
+            profile.push([radius, self.length]);
+        }
+        profile
+    }
+
+    // Lines in machine coordinates outlining the tool with its tip at `tip`,
+    // a ring at every corner of the profile and meridians along it
+    pub fn wireframe(&self, tip: [f32; 3]) -> Vec<[[f32; 3]; 2]> {
+        let profile = self.profile();
+        let point = |[radius, height]: [f32; 2], angle: f32| {
+            [
+                tip[0] + radius * angle.cos(),
+                tip[1] + radius * angle.sin(),
+                tip[2] + height,
+            ]
+        };
+        let mut lines = vec![];
+        for corner in profile.iter().filter(|corner| corner[0] > 0.0) {
+            for step in 0..24 {
+                let angle = |step: i32| step as f32 / 24.0 * 2.0 * PI;
+                lines.push([point(*corner, angle(step)), point(*corner, angle(step + 1))]);
+            }
+        }
+        for meridian in 0..8 {
+            let angle = meridian as f32 / 8.0 * 2.0 * PI;
+            for pair in profile.windows(2) {
+                lines.push([point(pair[0], angle), point(pair[1], angle)]);
+            }
+        }
+        lines
+    }
+}
+
+// Tools by number, read from a text file with a tool per line. Everything
+// after a semicolon is a comment.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ToolTable {
+    tools: Vec<Tool>,
+}
+
+impl ToolTable {
+    pub fn new() -> Self {
+        ToolTable { tools: vec![] }
+    }
+
+    // Fails with the first line that is not a tool
+    pub fn parse(contents: &str) -> Result<ToolTable, String> {
+        let mut table = ToolTable::new();
+        for (index, line) in contents.lines().enumerate() {
+            let text = line.split(';').next().unwrap_or("").trim();
+            if text.is_empty() {
+                continue;
+            }
+            match Tool::parse(text) {
+                Some(tool) => table.insert(tool),
+                None => return Err(format!("line {}: {}", index + 1, line)),
+            }
+        }
+        Ok(table)
+    }
+
+    // Replaces a tool with the same number
+    pub fn insert(&mut self, tool: Tool) {
+        self.tools.retain(|existing| existing.number != tool.number);
+        self.tools.push(tool);
+    }
+
+    pub fn get(&self, number: i32) -> Option<&Tool> {
+        self.tools.iter().find(|tool| tool.number == number)
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_table() {
+        let table = ToolTable::parse(
+            "; shop tools\nT1 flat D6 L20\n\nT3 drill d5 ; jobber\nT5 VBIT D6 A30\n",
+        )
+        .unwrap();
+        assert_eq!(3, table.tools().len());
+        let drill = table.get(3).unwrap();
+        assert_eq!(
+            (ToolShape::Drill, 5.0, 15.0),
+            (drill.shape, drill.diameter, drill.length)
+        );
+        assert_eq!(118.0, drill.angle);
+        assert_eq!(30.0, table.get(5).unwrap().angle);
+        assert_eq!(None, table.get(2));
+
+        assert_eq!(
+            Err("line 2: T2 ball".to_string()),
+            ToolTable::parse("T1 flat D6\nT2 ball\n")
+        );
+        assert!(ToolTable::parse("T1 taper D6").is_err());
+
+        // No tool without a size or with a flat or inverted tip
+        for line in [
+            "T1 flat D0",
+            "T1 flat D-6",
+            "T1 flat D6 L0",
+            "T1 flat D6 L-20",
+            "T5 vbit D6 A0",
+            "T5 vbit D6 A180",
+            "T5 vbit D6 A270",
+            "T5 vbit D6 A-30",
+        ]
+        .iter()
+        {
+            assert_eq!(Err(format!("line 1: {}", line)), ToolTable::parse(line));
+        }
+
+        let example = ToolTable::parse(include_str!("../examples/holepattern_tools.txt"));
+        assert_eq!(3, example.unwrap().tools().len());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let tool = |text| Tool::parse(text).unwrap();
+        assert_eq!(
+            vec![[0.0, 0.0], [3.0, 0.0], [3.0, 20.0]],
+            tool("T1 flat D6 L20").profile()
+        );
+
+        // A 90 degree chamfer is as high as its radius, a ball is round
+        let chamfer = tool("T4 chamfer D10 L2").profile();
+        assert_eq!(2, chamfer.len());
+        assert!((chamfer[1][1] - 5.0).abs() < 1e-4);
+        let ball = tool("T2 ball D4").profile();
+        assert!(ball
+            .iter()
+            .take(9)
+            .all(|[radius, height]| (radius.hypot(height - 2.0) - 2.0).abs() < 1e-4));
+
+        // Two rings and a line per meridian for a flat end mill
+        assert_eq!(2 * 24 + 8 * 2, tool("T1 flat D6").wireframe([0.0; 3]).len());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::events::MachineSink;
+use crate::history::Snapshot;
+use crate::simple_machine::MachineStatus;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+
+const AXES: [char; 4] = ['x', 'y', 'z', 'e'];
+
+// Signal index, step lines first, then dir lines, then enable
+fn step_signal(axis: usize) -> usize {
+    axis
+}
+
+fn dir_signal(axis: usize) -> usize {
+    4 + axis
+}
+
+const ENABLE: usize = 8;
+
+fn identifier(signal: usize) -> char {
+    (b'!' + signal as u8) as char
+}
+
+fn nanoseconds(seconds: f64) -> u64 {
+    (seconds * 1e9).round().max(0.0) as u64
+}
+
+// Value Change Dump of the step/dir pair per axis plus a shared enable line,
+// with a 1 ns timescale. Edges can be scheduled ahead of the step that causes
+// them, so they are kept in order until no earlier edge can arrive.
+pub struct VcdWriter<W: Write> {
+    out: W,
+    pulse_width: u64,
+    dir_setup: u64,
+    direction: [i32; 4],
+    step_free: [u64; 4],
+    pending: BinaryHeap<Reverse<(u64, u64, usize, u8)>>,
+    sequence: u64,
+    now: u64,
+    // After a failure or once finished, nothing more is written
+    closed: bool,
+    // The first failure, kept for `close` to return
+    error: Option<io::Error>,
+}
+
+impl<W: Write> VcdWriter<W> {
+    // Pulse width and dir setup time in seconds
+    pub fn new(mut out: W, pulse_width: f32, dir_setup: f32) -> io::Result<Self> {
+        writeln!(out, "$version gcode_simulator $end")?;
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module stepper $end")?;
+        for (axis, name) in AXES.iter().enumerate() {
+            writeln!(
+                out,
+                "$var wire 1 {} step_{} $end",
+                identifier(step_signal(axis)),
+                name
+            )?;
+            writeln!(
+                out,
+                "$var wire 1 {} dir_{} $end",
+                identifier(dir_signal(axis)),
+                name
+            )?;
+        }
+        writeln!(out, "$var wire 1 {} enable $end", identifier(ENABLE))?;
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        // Drivers enabled from the start, steps low, dir negative
+        writeln!(out, "#0")?;
+        writeln!(out, "$dumpvars")?;
+        for signal in 0..ENABLE {
+            writeln!(out, "0{}", identifier(signal))?;
+        }
+        writeln!(out, "1{}", identifier(ENABLE))?;
+        writeln!(out, "$end")?;
+
+        Ok(VcdWriter {
+            out,
+            pulse_width: nanoseconds(pulse_width as f64).max(1),
+            dir_setup: nanoseconds(dir_setup as f64),
+            direction: [-1; 4],
+            step_free: [0; 4],
+            pending: BinaryHeap::new(),
+            sequence: 0,
+            now: 0,
+            closed: false,
+            error: None,
+        })
+    }
+
+    fn schedule(&mut self, time: u64, signal: usize, value: u8) {
+        self.pending
+            .push(Reverse((time, self.sequence, signal, value)));
+        self.sequence += 1;
+    }
+
+    // Writes out every edge before `time`
+    fn flush_until(&mut self, time: u64) -> io::Result<()> {
+        while let Some(Reverse((edge, _, signal, value))) = self.pending.peek().cloned() {
+            if edge >= time {
+                break;
+            }
+            self.pending.pop();
+
+            // Timestamps from consecutive moves may overlap by rounding
+            let edge = edge.max(self.now);
+            if edge != self.now {
+                writeln!(self.out, "#{}", edge)?;
+                self.now = edge;
+            }
+            writeln!(self.out, "{}{}", value, identifier(signal))?;
+        }
+        Ok(())
+    }
+
+    // A step on `axis` in `direction`, `time` seconds since program start
+    pub fn step(&mut self, axis: usize, direction: i32, time: f64) -> io::Result<()> {
+        let time = nanoseconds(time);
+        self.flush_until(time.saturating_sub(self.dir_setup))?;
+
+        let mut rise = time.max(self.step_free[axis]);
+        if direction != self.direction[axis] {
+            // Dir changes once the previous pulse is over, and settles before the next
+            let change = rise
+                .saturating_sub(self.dir_setup)
+                .max(self.step_free[axis]);
+            self.schedule(change, dir_signal(axis), (direction > 0) as u8);
+            self.direction[axis] = direction;
+            rise = rise.max(change + self.dir_setup);
+        }
+
+        let fall = rise + self.pulse_width;
+        self.schedule(rise, step_signal(axis), 1);
+        self.schedule(fall, step_signal(axis), 0);
+        self.step_free[axis] = fall + 1;
+        Ok(())
+    }
+
+    // Writes the remaining edges and disables the drivers after the last one
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.closed = true;
+        self.flush_until(u64::MAX)?;
+        writeln!(self.out, "#{}", self.now + self.pulse_width)?;
+        writeln!(self.out, "0{}", identifier(ENABLE))?;
+        self.out.flush()
+    }
+
+    // Stops writing after the first failure
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(error) = result {
+            self.error.get_or_insert(error);
+            self.closed = true;
+        }
+    }
+
+    fn end(&mut self) {
+        if !self.closed {
+            let result = self.finish();
+            self.check(result);
+        }
+    }
+
+    // Finishes the dump unless the run or a jump ended it already, with the
+    // first failure since it was opened
+    pub fn close(&mut self) -> io::Result<()> {
+        self.end();
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+// A run stopped at a breakpoint or a closed window still ends the dump,
+// a failure this late goes unreported
+impl<W: Write> Drop for VcdWriter<W> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+impl<W: Write> MachineSink for VcdWriter<W> {
+    fn step(&mut self, axis: usize, direction: i32, time: f64) {
+        if !self.closed {
+            let result = VcdWriter::step(self, axis, direction, time);
+            self.check(result);
+        }
+    }
+
+    // The dump is one pass through the program, executing again from another
+    // point would go back in time. It ends at the first jump.
+    fn jumped(&mut self, _snapshot: &Snapshot) {
+        self.end();
+    }
+
+    fn finished(&mut self, _status: MachineStatus) {
+        self.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_machine::{SimpleMachine, ToolConfig};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_step_and_dir_edges() {
+        let mut vcd = VcdWriter::new(vec![], 2e-6, 1e-6).unwrap();
+        vcd.step(0, 1, 0.00001).unwrap();
+        vcd.step(0, 1, 0.00002).unwrap();
+        vcd.step(1, -1, 0.00002).unwrap();
+        vcd.finish().unwrap();
+        let text = String::from_utf8(vcd.out.clone()).unwrap();
+
+        let body = text.split("$end\n").last().unwrap();
+        assert_eq!(
+            "#9000\n1%\n#10000\n1!\n#12000\n0!\n#20000\n1!\n1\"\n#22000\n0!\n0\"\n#24000\n0)\n",
+            body
+        );
+        assert!(text.contains("$var wire 1 % dir_x $end"));
+        assert!(text.contains("$timescale 1ns $end"));
+    }
+
+    #[test]
+    fn test_edges_stay_ordered() {
+        // Pulses longer than the step interval are stretched, never reordered
+        let mut vcd = VcdWriter::new(vec![], 5e-6, 1e-6).unwrap();
+        for step in 0..10 {
+            let direction = if step % 3 == 0 { 1 } else { -1 };
+            vcd.step(step % 2, direction, step as f64 * 2e-6).unwrap();
+        }
+        vcd.finish().unwrap();
+        let text = String::from_utf8(vcd.out.clone()).unwrap();
+
+        let mut last = 0;
+        for line in text.lines().filter(|line| line.starts_with('#')) {
+            let time: u64 = line[1..].parse().unwrap();
+            assert!(time >= last);
+            last = time;
+        }
+    }
+
+    #[test]
+    fn test_jump_ends_dump() {
+        let vcd = Rc::new(RefCell::new(VcdWriter::new(vec![], 2e-6, 1e-6).unwrap()));
+        let mut machine = SimpleMachine::new(ToolConfig::new());
+        machine.load_str("G1 X1 F600\nG1 X2\n");
+        machine.add_sink(Box::new(vcd.clone()));
+        machine.step_block();
+        machine.step_back();
+        machine.run_to_end();
+
+        // Only the first pass of the first move, then the drivers disabled once
+        let text = String::from_utf8(vcd.borrow().out.clone()).unwrap();
+        let body = text.split("$end\n").last().unwrap();
+        assert_eq!(100, body.matches("1!").count());
+        assert_eq!(1, body.matches("0)").count());
+        assert!(body.ends_with("0)\n"));
+    }
+
+    #[test]
+    fn test_drop_ends_dump() {
+        // Stopped at a breakpoint, never finished
+        let mut out = vec![];
+        {
+            let mut vcd = VcdWriter::new(&mut out, 2e-6, 1e-6).unwrap();
+            vcd.step(0, 1, 0.00001).unwrap();
+        }
+        let text = String::from_utf8(out).unwrap();
+        let body = text.split("$end\n").last().unwrap();
+        assert_eq!("#9000\n1%\n#10000\n1!\n#12000\n0!\n#14000\n0)\n", body);
+    }
+
+    #[test]
+    fn test_close_returns_failure() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Err(io::Error::other("disk full"))
+            }
+        }
+        let mut vcd = VcdWriter::new(Full, 2e-6, 1e-6).unwrap();
+        MachineSink::finished(&mut vcd, MachineStatus::Finished);
+        assert_eq!("disk full", vcd.close().unwrap_err().to_string());
+        assert!(vcd.close().is_ok());
+    }
+}
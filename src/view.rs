@@ -0,0 +1,276 @@
+use crate::simple_machine::Bounds;
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+// Standard directions to look at the table from
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Preset {
+    Top,
+    Front,
+    Right,
+    Isometric,
+}
+
+impl Preset {
+    pub fn parse(name: &str) -> Option<Preset> {
+        match name.to_lowercase().as_str() {
+            "top" => Some(Preset::Top),
+            "front" => Some(Preset::Front),
+            "right" => Some(Preset::Right),
+            "iso" | "isometric" => Some(Preset::Isometric),
+            _ => None,
+        }
+    }
+
+    // Yaw about Z, then pitch about the screen X axis, radians
+    fn angles(self) -> (f32, f32) {
+        match self {
+            Preset::Top => (0.0, 0.0),
+            Preset::Front => (0.0, -FRAC_PI_2),
+            Preset::Right => (-FRAC_PI_2, -FRAC_PI_2),
+            // Looking down the diagonal from +X, -Y, +Z
+            Preset::Isometric => (-FRAC_PI_4, -(1.0 / 3f32.sqrt()).acos()),
+        }
+    }
+
+    pub fn next(self) -> Preset {
+        match self {
+            Preset::Top => Preset::Front,
+            Preset::Front => Preset::Right,
+            Preset::Right => Preset::Isometric,
+            Preset::Isometric => Preset::Top,
+        }
+    }
+}
+
+// Camera orbiting a point of the machine, projects machine coordinates to
+// pixels. Whatever is drawn stays in machine coordinates, only the camera moves.
+// Looking from the top X points right and Y up.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct View {
+    // Pixels per machine unit at the target
+    pub scale: f32,
+    // Pixel the target lands on
+    pub center: (f32, f32),
+    pub target: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+    pub perspective: bool,
+    // From the camera to the target in perspective, machine units
+    pub distance: f32,
+    // Pixels left around the program when fitting it
+    pub margin: f32,
+}
+
+impl View {
+    // From the top, the machine origin in the middle of a `width` by `height` image
+    pub fn new(width: u32, height: u32) -> Self {
+        View {
+            scale: 4.0,
+            center: (0.5 * width as f32, 0.5 * height as f32),
+            target: [0.0; 3],
+            yaw: 0.0,
+            pitch: 0.0,
+            perspective: false,
+            distance: 500.0,
+            margin: 20.0,
+        }
+    }
+
+    // Relative to the target along the camera axes, X right, Y up and Z
+    // towards the camera
+    pub fn camera_point(&self, point: [f32; 3]) -> [f32; 3] {
+        let x = point[0] - self.target[0];
+        let y = point[1] - self.target[1];
+        let z = point[2] - self.target[2];
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (x, y) = (x * cos_yaw - y * sin_yaw, x * sin_yaw + y * cos_yaw);
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        [
+            x,
+            y * cos_pitch - z * sin_pitch,
+            y * sin_pitch + z * cos_pitch,
+        ]
+    }
+
+    // Magnification of a point `depth` towards the camera from the target
+    fn perspective_factor(&self, depth: f32) -> f32 {
+        if self.perspective {
+            self.distance / (self.distance - depth).max(0.1 * self.distance)
+        } else {
+            1.0
+        }
+    }
+
+    pub fn screen_point(&self, x: f32, y: f32, z: f32) -> (f32, f32) {
+        let [x, y, depth] = self.camera_point([x, y, z]);
+        let scale = self.scale * self.perspective_factor(depth);
+        (self.center.0 + x * scale, self.center.1 - y * scale)
+    }
+
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        self.center.0 += dx;
+        self.center.1 += dy;
+    }
+
+    // Keeps the middle of a `from` image in the middle of the `to` image it
+    // is resized to, at the same scale
+    pub fn resize(&mut self, from: (u32, u32), to: (u32, u32)) {
+        self.pan(
+            0.5 * (to.0 as f32 - from.0 as f32),
+            0.5 * (to.1 as f32 - from.1 as f32),
+        );
+    }
+
+    // Puts `point` in the middle of a `width` by `height` image, at the same
+    // scale and from the same direction
+    pub fn look_at(&mut self, point: [f32; 3], width: u32, height: u32) {
+        self.target = point;
+        self.center = (0.5 * width as f32, 0.5 * height as f32);
+    }
+
+    // Keeps what is under the pixel `x`, `y` in the target plane where it is
+    pub fn zoom(&mut self, factor: f32, x: f32, y: f32) {
+        let plane = (
+            (x - self.center.0) / self.scale,
+            (self.center.1 - y) / self.scale,
+        );
+        self.scale = (self.scale * factor).clamp(0.01, 1000.0);
+        self.center = (x - plane.0 * self.scale, y + plane.1 * self.scale);
+    }
+
+    // Turns around the target by a mouse drag of `dx`, `dy` pixels, never
+    // further down than looking up from below
+    pub fn orbit(&mut self, dx: f32, dy: f32) {
+        self.yaw += 0.01 * dx;
+        self.pitch = (self.pitch + 0.01 * dy).clamp(-PI, 0.0);
+    }
+
+    pub fn set_preset(&mut self, preset: Preset) {
+        let (yaw, pitch) = preset.angles();
+        self.yaw = yaw;
+        self.pitch = pitch;
+    }
+
+    pub fn toggle_perspective(&mut self) {
+        self.perspective = !self.perspective;
+    }
+
+    // Frames `bounds` in a `width` by `height` image from the current direction
+    pub fn fit(&mut self, bounds: &Bounds, width: u32, height: u32) {
+        let margin = self.margin;
+        self.target = [
+            0.5 * (bounds.min[0] + bounds.max[0]),
+            0.5 * (bounds.min[1] + bounds.max[1]),
+            0.5 * (bounds.min[2] + bounds.max[2]),
+        ];
+        let size = bounds.size();
+        let diagonal = (size[0] * size[0] + size[1] * size[1] + size[2] * size[2]).sqrt();
+        self.distance = 3.0 * diagonal.max(10.0);
+
+        let mut half = (0.0f32, 0.0f32);
+        for corner in 0..8 {
+            let pick = |axis: usize| {
+                if corner & (1 << axis) == 0 {
+                    bounds.min[axis]
+                } else {
+                    bounds.max[axis]
+                }
+            };
+            let point = [pick(0), pick(1), pick(2)];
+            let [x, y, depth] = self.camera_point(point);
+            let factor = self.perspective_factor(depth);
+            half = (half.0.max(x.abs() * factor), half.1.max(y.abs() * factor));
+        }
+
+        // A single point or line keeps the scale along its missing dimension
+        let scale =
+            ((0.5 * width as f32 - margin) / half.0).min((0.5 * height as f32 - margin) / half.1);
+        if scale.is_finite() && scale > 0.0 {
+            self.scale = scale.clamp(0.01, 1000.0);
+        }
+        self.center = (0.5 * width as f32, 0.5 * height as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(expected: (f32, f32), actual: (f32, f32)) {
+        assert!(
+            (expected.0 - actual.0).abs() < 1e-3 && (expected.1 - actual.1).abs() < 1e-3,
+            "{:?} != {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_top_view() {
+        let mut view = View::new(800, 600);
+        assert_eq!((440.0, 280.0), view.screen_point(10.0, 5.0, -3.0));
+
+        // Every quadrant around the origin is on screen, Y up
+        assert_eq!((360.0, 320.0), view.screen_point(-10.0, -5.0, 0.0));
+        assert_eq!((360.0, 280.0), view.screen_point(-10.0, 5.0, 0.0));
+        assert_eq!((440.0, 320.0), view.screen_point(10.0, -5.0, 0.0));
+
+        // Zooming keeps the point under the pointer, panning moves everything
+        view.zoom(2.0, 440.0, 280.0);
+        assert_eq!((440.0, 280.0), view.screen_point(10.0, 5.0, 0.0));
+        assert_eq!(8.0, view.scale);
+        view.pan(-50.0, 10.0);
+        assert_eq!((390.0, 290.0), view.screen_point(10.0, 5.0, 0.0));
+        view.resize((800, 600), (1000, 500));
+        assert_eq!((490.0, 240.0), view.screen_point(10.0, 5.0, 0.0));
+        view.look_at([10.0, 5.0, 0.0], 600, 400);
+        assert_eq!((300.0, 200.0), view.screen_point(10.0, 5.0, 0.0));
+        assert_eq!((340.0, 200.0), view.screen_point(15.0, 5.0, 0.0));
+
+        // The program fills the image less the margin, centered
+        let mut bounds = Bounds::new([-100.0, -10.0, 0.0]);
+        bounds.extend([100.0, 10.0, 0.0]);
+        view.fit(&bounds, 800, 600);
+        assert_eq!(3.8, view.scale);
+        assert_near((20.0, 338.0), view.screen_point(-100.0, -10.0, 0.0));
+        assert_near((780.0, 262.0), view.screen_point(100.0, 10.0, 0.0));
+
+        // A program that does not move keeps the scale
+        view.fit(&Bounds::new([0.0; 3]), 800, 600);
+        assert_eq!(3.8, view.scale);
+        assert_eq!((400.0, 300.0), view.screen_point(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_presets_and_perspective() {
+        let mut view = View::new(800, 600);
+
+        // From the front Z is up, from the right Y points right
+        view.set_preset(Preset::Front);
+        assert_near((440.0, 320.0), view.screen_point(10.0, 7.0, -5.0));
+        view.set_preset(Preset::Right);
+        assert_near((420.0, 320.0), view.screen_point(10.0, 5.0, -5.0));
+
+        // Isometric keeps the three axes the same length on screen
+        view.set_preset(Preset::Isometric);
+        let origin = view.screen_point(0.0, 0.0, 0.0);
+        let length = |point: (f32, f32)| (point.0 - origin.0).hypot(point.1 - origin.1);
+        let x = length(view.screen_point(10.0, 0.0, 0.0));
+        assert!((x - length(view.screen_point(0.0, 10.0, 0.0))).abs() < 1e-3);
+        assert!((x - length(view.screen_point(0.0, 0.0, 10.0))).abs() < 1e-3);
+        assert_eq!(Preset::Top, Preset::Isometric.next());
+        assert_eq!(Some(Preset::Isometric), Preset::parse("Iso"));
+        assert_eq!(None, Preset::parse("left"));
+
+        // Closer is bigger in perspective, the target plane stays put
+        view.set_preset(Preset::Top);
+        view.toggle_perspective();
+        assert_eq!((440.0, 300.0), view.screen_point(10.0, 0.0, 0.0));
+        assert!(view.screen_point(10.0, 0.0, 100.0).0 > 440.0);
+        assert!(view.screen_point(10.0, 0.0, -100.0).0 < 440.0);
+
+        // Orbiting stops at looking straight up from below
+        view.orbit(0.0, -1000.0);
+        assert_eq!(-PI, view.pitch);
+    }
+}
@@ -1,184 +1,930 @@
-use crate::simple_machine;
+use gcode_simulator::breakpoints::Condition;
+use gcode_simulator::events::MachineSink;
+use gcode_simulator::gcode::Listing;
+use gcode_simulator::render::{Line, ToolTrail};
+use gcode_simulator::simple_machine;
+use gcode_simulator::view::{Preset, View};
 use std::sync::mpsc;
 
 extern crate sfml;
 
 use sfml::graphics::{
-    Color, Drawable, Image, RenderStates, RenderTarget, RenderWindow, Sprite, Texture,
+    Color, FloatRect, Font, PrimitiveType, RectangleShape, RenderStates, RenderTarget,
+    RenderWindow, Shape, Text, Transformable, Vertex,
 };
 use sfml::system::SfBox;
-use sfml::window::{Event, Key, Style};
+use sfml::window::{mouse, Event, Key, Style, VideoMode};
 
-#[derive(Debug, PartialEq)]
-struct Position {
-    x: f32,
-    y: f32,
-    z: f32,
+fn draw_lines(target: &mut dyn RenderTarget, lines: &[Line]) {
+    let mut vertices = Vec::with_capacity(2 * lines.len());
+    for line in lines.iter() {
+        let colour = Color::rgb(line.colour.r, line.colour.g, line.colour.b);
+        vertices.push(Vertex::with_pos_color(line.from, colour));
+        vertices.push(Vertex::with_pos_color(line.to, colour));
+    }
+    target.draw_primitives(&vertices, PrimitiveType::Lines, RenderStates::default());
+}
+
+// Snapshot before the block executing at the playback clock
+fn executing_snapshot(machine: &simple_machine::SimpleMachine, clock: f32) -> usize {
+    machine.history().find_time(clock).unwrap_or(0)
+}
+
+// Readout of the machine at the playback clock, like the DRO screen of a
+// control: block and time, position, feed, spindle, tool and modal G codes.
+// Work and machine coordinates are the same, there are no work offsets.
+fn dro_lines(
+    machine: &simple_machine::SimpleMachine,
+    clock: f32,
+    tool: &simple_machine::ToolState,
+) -> Vec<String> {
+    let history = machine.history();
+    let snapshots = history.snapshots();
+    let index = executing_snapshot(machine, clock);
+    let snapshot = &snapshots[index];
+    // The executing block has set its feed, tool and motion by the time it ends
+    let executing = snapshots.get(index + 1).unwrap_or(snapshot);
+    let overrides = machine.overrides();
+    let modal = &snapshot.modal;
+
+    let mut lines = vec![format!(
+        "N{:<6} {:>8.1} / {:.1} s",
+        snapshot.block,
+        clock,
+        history.end_time()
+    )];
+    let position = [tool.x, tool.y, tool.z, tool.e];
+    for (axis, value) in ['X', 'Y', 'Z', 'E'].iter().zip(position.iter()) {
+        lines.push(format!("{} {:>10.3}", axis, value));
+    }
+    lines.push(format!(
+        "F{} ({}) V{:.0}",
+        executing.state.feedrate,
+        executing.state.feedrate * overrides.feed / 100.0,
+        tool.velocity
+    ));
+    lines.push(format!(
+        "S{} ({}) {} {}",
+        modal.spindle_speed,
+        modal.spindle_speed * overrides.spindle / 100.0,
+        modal.spindle,
+        modal.coolant
+    ));
+    lines.push(format!(
+        "T{} next T{}",
+        executing.state.tool, modal.selected_tool
+    ));
+    let codes = &executing.modal;
+    lines.push(format!(
+        "{} {} {} {} {}",
+        codes.motion_mode, codes.plane, codes.units, codes.distance_mode, codes.path_mode
+    ));
+    lines
+}
+
+// Monospaced fonts tried for the DRO when none is given
+const FONTS: [&str; 4] = [
+    "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf",
+    "/usr/share/fonts/TTF/DejaVuSansMono.ttf",
+    "/System/Library/Fonts/Menlo.ttc",
+    "C:\\Windows\\Fonts\\consola.ttf",
+];
+
+fn load_font(path: &Option<String>) -> Option<SfBox<Font>> {
+    match path {
+        Some(path) => Font::from_file(path),
+        None => FONTS
+            .iter()
+            .filter(|path| std::path::Path::new(path).exists())
+            .find_map(|path| Font::from_file(path)),
+    }
 }
 
-struct ToolTrail {
-    trail: Vec<Position>,
-    tool: simple_machine::ToolState,
-    texture: Option<SfBox<Texture>>,
+// Scrollable program text next to the view, the executing line highlighted
+// in yellow and the picked one in blue
+struct ListingPane {
+    listing: Listing,
+    // First line shown
+    top: usize,
+    current: Option<usize>,
+    selected: Option<usize>,
 }
 
-impl ToolTrail {
-    pub fn new() -> Self {
-        ToolTrail {
-            trail: vec![],
-            tool: simple_machine::ToolState::new(),
-            texture: Texture::new(800, 600),
+impl ListingPane {
+    fn new(listing: &Listing) -> Self {
+        ListingPane {
+            listing: listing.clone(),
+            top: 0,
+            current: None,
+            selected: None,
         }
     }
 
-    pub fn add(&mut self, tool: &simple_machine::ToolState) {
-        let scale = 4.0;
-        self.trail.push(Position {
-            x: scale * tool.x,
-            y: scale * tool.y,
-            z: scale * tool.z,
-        });
+    fn line_of(&self, block: i32) -> Option<usize> {
+        self.listing
+            .iter()
+            .position(|line| line.block == Some(block))
+    }
+
+    // Scrolls `line` into view when it is not, a third down from the top
+    fn reveal(&mut self, line: usize, rows: usize) {
+        if line < self.top || line >= self.top + rows {
+            self.top = line.saturating_sub(rows / 3);
+        }
+    }
+
+    // Highlights the line of the executing block, scrolling along when it changes
+    fn follow(&mut self, block: i32, rows: usize) {
+        let line = self.line_of(block);
+        if line != self.current {
+            self.current = line;
+            if let Some(line) = line {
+                self.reveal(line, rows);
+            }
+        }
+    }
+
+    fn select_block(&mut self, block: Option<i32>, rows: usize) {
+        self.selected = block.and_then(|block| self.line_of(block));
+        if let Some(line) = self.selected {
+            self.reveal(line, rows);
+        }
+    }
+
+    fn scroll(&mut self, lines: i32) {
+        let top = (self.top as i32 + lines).max(0) as usize;
+        self.top = top.min(self.listing.len().saturating_sub(1));
+    }
+
+    // Selects the line in `row`, returns its block
+    fn click(&mut self, row: usize) -> Option<i32> {
+        let line = self.top + row;
+        let block = self.listing.get(line)?.block;
+        self.selected = Some(line);
+        block
+    }
+
+    // Block labels before the text, comments and blank lines in grey
+    fn draw(
+        &self,
+        target: &mut dyn RenderTarget,
+        font: &Font,
+        area: &FloatRect,
+        character_size: u32,
+    ) {
+        let mut panel = RectangleShape::with_size((area.width, area.height).into());
+        panel.set_position((area.left, area.top));
+        panel.set_fill_color(Color::rgb(250, 250, 250));
+        panel.set_outline_color(Color::rgb(160, 160, 160));
+        panel.set_outline_thickness(1.0);
+        target.draw(&panel);
+
+        let line_height = font.line_spacing(character_size) as f32;
+        let rows = (area.height / line_height).ceil() as usize;
+        for (row, line) in self.listing.iter().enumerate().skip(self.top).take(rows) {
+            let y = area.top + (row - self.top) as f32 * line_height;
+            let background = if Some(row) == self.selected {
+                Some(Color::rgb(190, 215, 255))
+            } else if Some(row) == self.current {
+                Some(Color::rgb(255, 235, 150))
+            } else {
+                None
+            };
+            if let Some(background) = background {
+                let mut highlight = RectangleShape::with_size((area.width, line_height).into());
+                highlight.set_position((area.left, y));
+                highlight.set_fill_color(background);
+                target.draw(&highlight);
+            }
+
+            let label = match line.block {
+                Some(block) => format!("{:>5} {}", block, line.text),
+                None => format!("{:>5} {}", "", line.text),
+            };
+            let mut text = Text::new(&label, font, character_size);
+            text.set_fill_color(match line.block {
+                Some(_) => Color::BLACK,
+                None => Color::rgb(120, 120, 120),
+            });
+            text.set_position((area.left + 4.0, y));
+            target.draw(&text);
+        }
+    }
+}
+
+// Black text on a light panel in the top left corner
+fn draw_dro(target: &mut dyn RenderTarget, font: &Font, lines: &[String], ui_scale: f32) {
+    let mut text = Text::new(&lines.join("\n"), font, (14.0 * ui_scale) as u32);
+    text.set_fill_color(Color::BLACK);
+    text.set_position((10.0 * ui_scale, 10.0 * ui_scale));
+    let bounds = text.global_bounds();
+    let padding = 5.0 * ui_scale;
+    let mut panel = RectangleShape::with_size(
+        (bounds.width + 2.0 * padding, bounds.height + 2.0 * padding).into(),
+    );
+    panel.set_position((bounds.left - padding, bounds.top - padding));
+    panel.set_fill_color(Color::rgba(240, 240, 240, 220));
+    panel.set_outline_color(Color::rgb(160, 160, 160));
+    panel.set_outline_thickness(1.0);
+    target.draw(&panel);
+    target.draw(&text);
+}
+
+// What the simulation pushes to the window, the tool position it takes from
+// the recorded motion instead
+pub enum GuiUpdate {
+    Config(simple_machine::ToolConfig),
+}
+
+// Feeds the window configuration changes
+pub struct GuiSink {
+    sender: mpsc::Sender<GuiUpdate>,
+    // Cleared once the window is gone, the simulation carries on without it
+    connected: bool,
+}
+
+impl GuiSink {
+    pub fn new(sender: mpsc::Sender<GuiUpdate>) -> Self {
+        GuiSink {
+            sender,
+            connected: true,
+        }
+    }
 
-        self.tool = tool.clone();
-        self.tool.x = scale * tool.x;
-        self.tool.y = scale * tool.y;
-        self.tool.z = scale * tool.z;
-    }
-
-    fn draw_square(image: &mut Image, x: u32, y: u32) {
-        image.set_pixel(x, y, Color::RED);
-        image.set_pixel(x, y + 1, Color::RED);
-        image.set_pixel(x, y - 1, Color::RED);
-
-        image.set_pixel(x + 1, y, Color::RED);
-        image.set_pixel(x + 1, y + 1, Color::RED);
-        image.set_pixel(x + 1, y - 1, Color::RED);
-
-        image.set_pixel(x - 1, y, Color::RED);
-        image.set_pixel(x - 1, y + 1, Color::RED);
-        image.set_pixel(x - 1, y - 1, Color::RED);
-    }
-
-    pub fn update_texture(&mut self) {
-        match Image::from_color(800, 600, Color::WHITE) {
-            Some(mut image) => {
-                let offset_x = 10;
-                let offset_y = 10;
-                for pos in &self.trail {
-                    if pos.z < 1.0 {
-                        if ((pos.x > 0.0) && (pos.y > 0.0)) {
-                            image.set_pixel(
-                                offset_x + pos.x as u32,
-                                offset_y + pos.y as u32,
-                                Color::BLUE,
-                            );
+    fn send(&mut self, update: GuiUpdate) {
+        if self.connected && self.sender.send(update).is_err() {
+            self.connected = false;
+        }
+    }
+}
+
+impl MachineSink for GuiSink {
+    fn config(&mut self, toolconfig: &simple_machine::ToolConfig) {
+        self.send(GuiUpdate::Config(toolconfig.clone()));
+    }
+}
+
+// Tool at the playback clock, where the recorded motion has it and with the
+// tool, feed and time of the block executing then
+fn tool_at(
+    machine: &simple_machine::SimpleMachine,
+    clock: f32,
+    followed: &simple_machine::ToolState,
+) -> simple_machine::ToolState {
+    let mut tool = machine.history().snapshots()[executing_snapshot(machine, clock)]
+        .state
+        .clone();
+    tool.x = followed.x;
+    tool.y = followed.y;
+    tool.z = followed.z;
+    tool.e = followed.e;
+    tool.velocity = followed.velocity;
+    tool.time = clock;
+    tool
+}
+
+// Steps executed in one frame before playback waits for the next, so a long
+// program does not hold up the window. Blocks are not split, a single block
+// with more steps still runs in one go.
+const STEPS_PER_FRAME: usize = 20_000;
+
+// Playback controls, after the cycle start, feed hold and single block buttons
+// of a control panel. The machine only executes blocks as playback needs them.
+struct Playback {
+    // Cycle start, cleared on feed hold
+    running: bool,
+    single_block: bool,
+    // Simulated seconds per real second
+    speed: f32,
+    run_to: Option<i32>,
+    line_input: String,
+    // Simulated time drawn up to
+    clock: f32,
+    // No more blocks this cycle, hold once the executed ones are drawn
+    cycle_done: bool,
+    // The clock moved other than by playing, the drawing has to catch up
+    jumped: bool,
+}
+
+impl Playback {
+    fn new() -> Self {
+        Playback {
+            running: true,
+            single_block: false,
+            speed: 1.0,
+            run_to: None,
+            line_input: String::new(),
+            clock: 0.0,
+            cycle_done: false,
+            jumped: false,
+        }
+    }
+
+    fn cycle_start(&mut self) {
+        self.running = true;
+        self.cycle_done = false;
+    }
+
+    fn feed_hold(&mut self) {
+        self.running = false;
+    }
+
+    fn run_to_line(&mut self, line: i32) {
+        self.run_to = Some(line);
+        self.cycle_start();
+    }
+
+    // Holds at `clock` once the machine has moved there
+    fn jumped_to(&mut self, clock: f32) {
+        self.feed_hold();
+        self.run_to = None;
+        self.clock = clock;
+        self.jumped = true;
+    }
+
+    fn scrub(&mut self, machine: &mut simple_machine::SimpleMachine, seconds: f32) {
+        let clock = (self.clock + seconds).max(0.0);
+        machine.jump_to_time(clock);
+        self.jumped_to(clock.min(machine.history().end_time()));
+    }
+
+    // Space toggles cycle start and feed hold, S single block, + and - the
+    // speed, a line number followed by Enter runs to that line, followed by B
+    // toggles a breakpoint on it and followed by G goes to its start. Comma
+    // steps back a block, [ and ] scrub a second back and forth. A percentage
+    // followed by F, R or O sets the feed, rapid or spindle override
+    fn handle_text(&mut self, machine: &mut simple_machine::SimpleMachine, text: char) {
+        match text {
+            ' ' if self.running => self.feed_hold(),
+            ' ' => self.cycle_start(),
+            's' | 'S' => self.single_block = !self.single_block,
+            '+' | '=' => self.speed = (self.speed * 2.0).min(64.0),
+            '-' => self.speed = (self.speed / 2.0).max(1.0 / 16.0),
+            '0'..='9' => self.line_input.push(text),
+            '\u{8}' => {
+                self.line_input.pop();
+            }
+            '\r' | '\n' => {
+                if let Ok(line) = self.line_input.parse() {
+                    self.run_to_line(line);
+                }
+                self.line_input.clear();
+            }
+            'b' | 'B' => {
+                if let Ok(line) = self.line_input.parse() {
+                    let existing = machine
+                        .breakpoints()
+                        .iter()
+                        .find(|breakpoint| breakpoint.condition == Condition::Block(line))
+                        .map(|breakpoint| breakpoint.id);
+                    match existing {
+                        Some(id) => machine.remove_breakpoint(id),
+                        None => {
+                            machine.add_breakpoint(Condition::Block(line));
+                            true
                         }
-                    }
+                    };
                 }
-
-                if ((self.tool.x > 0.0) && (self.tool.y > 0.0)) {
-                    ToolTrail::draw_square(
-                        &mut image,
-                        offset_x + self.tool.x as u32,
-                        offset_y + self.tool.y as u32,
-                    );
+                self.line_input.clear();
+            }
+            'g' | 'G' => {
+                if let Ok(line) = self.line_input.parse() {
+                    machine.jump_to_block(line);
+                    self.jumped_to(machine.toolstate().time);
                 }
-
-                match &mut self.texture {
-                    Some(texture) => {
-                        texture.update_from_image(&image, 0, 0);
-                    }
-                    None => (),
+                self.line_input.clear();
+            }
+            ',' => {
+                machine.step_back();
+                self.jumped_to(machine.toolstate().time);
+            }
+            'f' | 'F' | 'r' | 'R' | 'o' | 'O' => {
+                let percent = self.line_input.parse().unwrap_or(100.0);
+                let mut overrides = *machine.overrides();
+                match text.to_ascii_uppercase() {
+                    'F' => overrides.feed = percent,
+                    'R' => overrides.rapid = percent,
+                    _ => overrides.spindle = percent,
                 }
+                machine.set_overrides(overrides);
+                self.line_input.clear();
             }
-            None => (),
+            '[' => self.scrub(machine, -1.0),
+            ']' => self.scrub(machine, 1.0),
+            _ => {}
         }
     }
 
-    pub fn draw(&self) -> Sprite {
-        let mut sprite = Sprite::new();
-        sprite.set_color(Color::WHITE);
+    // Advances the clock by a frame, executing blocks to stay ahead of it
+    fn advance(&mut self, machine: &mut simple_machine::SimpleMachine, frame: f32) {
+        if !self.running {
+            return;
+        }
+        self.clock += frame * self.speed;
+
+        let steps = machine.history().recorded_steps();
+        while !self.cycle_done
+            && matches!(
+                machine.status(),
+                simple_machine::MachineStatus::Running | simple_machine::MachineStatus::Break(_)
+            )
+            && machine.toolstate().time <= self.clock
+            && machine.history().recorded_steps() - steps < STEPS_PER_FRAME
+        {
+            if let Some(line) = self.run_to {
+                if machine.current_block() >= line {
+                    self.run_to = None;
+                    self.cycle_done = true;
+                    break;
+                }
+            }
+            let status = machine.step_block();
+            if self.single_block || matches!(status, simple_machine::MachineStatus::Break(_)) {
+                self.cycle_done = true;
+            }
+        }
+
+        let end = machine.toolstate().time;
+        if self.clock >= end {
+            self.clock = end;
+            if self.cycle_done {
+                self.running = false;
+            }
+        }
+    }
 
-        match &self.texture {
-            Some(texture) => sprite.set_texture(&texture, true),
-            None => (),
+    fn title(&self, machine: &simple_machine::SimpleMachine) -> String {
+        let state = match machine.status() {
+            simple_machine::MachineStatus::Finished => "Finished".to_string(),
+            simple_machine::MachineStatus::Halted => "Halted".to_string(),
+            simple_machine::MachineStatus::Break(id) => format!("Break {}", id),
+            simple_machine::MachineStatus::Running if self.running => "Cycle start".to_string(),
+            simple_machine::MachineStatus::Running => "Feed hold".to_string(),
         };
-        return sprite;
+        let mut title = format!(
+            "SimpleMachine - {} N{} {:.1} s x{}",
+            state,
+            machine.current_block(),
+            self.clock,
+            self.speed
+        );
+        title.push_str(&format!(
+            " F{} ({})",
+            machine.toolstate().feedrate,
+            machine.effective_feedrate()
+        ));
+        let overrides = machine.overrides();
+        if overrides.rapid != 100.0 {
+            title.push_str(&format!(" rapid {}%", overrides.rapid));
+        }
+        if machine.modal_state().spindle_speed > 0.0 {
+            title.push_str(&format!(
+                " S{} ({})",
+                machine.modal_state().spindle_speed,
+                machine.effective_spindle_speed()
+            ));
+        }
+        if self.single_block {
+            title.push_str(" single block");
+        }
+        if let Some(line) = self.run_to {
+            title.push_str(&format!(" to N{}", line));
+        }
+        if !self.line_input.is_empty() {
+            title.push_str(&format!(" N{}_", self.line_input));
+        }
+        title
     }
 }
 
-impl Drawable for ToolTrail {
-    fn draw<'a: 'shader, 'texture, 'shader, 'shader_texture>(
-        &'a self,
-        render_target: &mut dyn RenderTarget,
-        _: RenderStates<'texture, 'shader, 'shader_texture>,
-    ) {
-        render_target.draw(&self.draw());
-    }
+// How the window opens, from the command line
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct WindowSettings {
+    // Size of the window and of what is drawn at a fixed size, from the
+    // desktop resolution when not given
+    pub ui_scale: Option<f32>,
+    pub fullscreen: bool,
+    // For the DRO, a common monospaced font when not given
+    pub font: Option<String>,
+}
+
+// 1 up to 1080 lines, 2 on a 4K display
+fn ui_scale_for(desktop_height: u32) -> f32 {
+    (desktop_height as f32 / 1080.0).max(1.0)
 }
 
+// Full screen at the desktop resolution, otherwise a resizable window of `size`
+fn open_window(size: (u32, u32), fullscreen: bool) -> RenderWindow {
+    let (mode, style) = if fullscreen {
+        (VideoMode::desktop_mode(), Style::FULLSCREEN)
+    } else {
+        (VideoMode::new(size.0, size.1, 32), Style::DEFAULT)
+    };
+    let mut window = RenderWindow::new(mode, "SimpleMachine", style, &Default::default());
+    window.set_vertical_sync_enabled(true);
+    window
+}
+
+// Runs until the window is closed, playing the machine back as it goes
 pub fn setup_window(
-    toolstate: mpsc::Receiver<simple_machine::SyncEntry>,
-    config_sync: mpsc::Receiver<simple_machine::ToolConfig>,
+    machine: &mut simple_machine::SimpleMachine,
+    updates: mpsc::Receiver<GuiUpdate>,
+    settings: WindowSettings,
 ) {
-    // Define some constants
-    let width = 800;
-    let height = 600;
-
-    let mut window = RenderWindow::new(
-        (width, height),
-        "SimpleMachine",
-        Style::CLOSE,
-        &Default::default(),
-    );
-    window.set_vertical_sync_enabled(true);
+    let ui_scale = match settings.ui_scale {
+        Some(ui_scale) => ui_scale,
+        None => ui_scale_for(VideoMode::desktop_mode().height),
+    };
+    let windowed = ((800.0 * ui_scale) as u32, (600.0 * ui_scale) as u32);
+    let mut fullscreen = settings.fullscreen;
+    let mut window = open_window(windowed, fullscreen);
+    let mut size = (window.size().x, window.size().y);
+
+    let font = load_font(&settings.font);
+    if font.is_none() {
+        println!("No font for the DRO and listing, pass one with --font");
+    }
+    let mut dro = true;
+    let mut pane = ListingPane::new(machine.listing());
+    let mut listing_shown = font.is_some() && !machine.listing().is_empty();
+    let character_size = (13.0 * ui_scale) as u32;
+    let line_height = match &font {
+        Some(font) => font.line_spacing(character_size).max(1) as f32,
+        None => 1.0,
+    };
+    // The view takes what the listing leaves of the window
+    let view_area = |size: (u32, u32), listing_shown: bool| {
+        if listing_shown {
+            (size.0 - ((320.0 * ui_scale) as u32).min(size.0 / 2), size.1)
+        } else {
+            size
+        }
+    };
+    let mut area = view_area(size, listing_shown);
 
-    let mut is_running = true;
-    let mut current_state = simple_machine::ToolState::new();
-    let mut tooltrail = ToolTrail::new();
-    let mut sample = 0;
-    let sample_frequency = 200;
+    let mut tooltrail = ToolTrail::new(machine);
+    tooltrail.set_ui_scale(ui_scale);
+    let mut view = View::new(area.0, area.1);
+    view.margin = 20.0 * ui_scale;
+    view.fit(&machine.program_bounds(), area.0, area.1);
+    let mut preset = Preset::Top;
+    // Last mouse position while dragging the view, and whether it orbits
+    let mut drag: Option<(i32, i32, bool)> = None;
+    // Where the left button went down in the view, released there it picks
+    let mut pressed: Option<(i32, i32)> = None;
+    let mut playback = Playback::new();
+    let mut title = String::new();
+    let frame_interval = 1.0 / 60.0;
 
-    while is_running {
+    loop {
         while let Some(event) = window.poll_event() {
             match event {
                 Event::Closed
                 | Event::KeyPressed {
                     code: Key::Escape, ..
                 } => return,
+                // Drawing stays in pixels whatever the size, the program stays
+                // in the middle. F11 toggles full screen
+                Event::Resized { width, height } => {
+                    size = (width, height);
+                    let resized = view_area(size, listing_shown);
+                    view.resize(area, resized);
+                    area = resized;
+                    let pixels = FloatRect::new(0.0, 0.0, width as f32, height as f32);
+                    window.set_view(&sfml::graphics::View::from_rect(&pixels));
+                }
+                Event::KeyPressed { code: Key::F11, .. } => {
+                    fullscreen = !fullscreen;
+                    window = open_window(windowed, fullscreen);
+                    size = (window.size().x, window.size().y);
+                    let resized = view_area(size, listing_shown);
+                    view.resize(area, resized);
+                    area = resized;
+                    title.clear();
+                }
+                // The mouse wheel zooms around the pointer, dragging with the left
+                // button pans, with the right one orbits. A fits the program, V
+                // steps through the standard views and P toggles perspective.
+                // Clicking a move highlights it and its line in the listing,
+                // clicking a line brings its move to the middle of the view.
+                Event::MouseWheelScrolled { delta, x, .. } if x >= area.0 as i32 => {
+                    pane.scroll((-3.0 * delta) as i32)
+                }
+                Event::MouseWheelScrolled { delta, x, y, .. } => {
+                    view.zoom(1.25f32.powf(delta), x as f32, y as f32)
+                }
+                Event::MouseButtonPressed { y, x, .. } if x >= area.0 as i32 => {
+                    let block = pane.click((y as f32 / line_height) as usize);
+                    tooltrail.set_highlight(block);
+                    if let Some(point) = block.and_then(|block| tooltrail.block_center(block)) {
+                        view.look_at(point, area.0, area.1);
+                    }
+                }
+                Event::MouseButtonPressed { button, x, y } => {
+                    drag = Some((x, y, button == mouse::Button::Right));
+                    if button == mouse::Button::Left {
+                        pressed = Some((x, y));
+                    }
+                }
+                Event::MouseButtonReleased { x, y, .. } => {
+                    drag = None;
+                    if let Some((from_x, from_y)) = pressed.take() {
+                        if (x - from_x).abs() + (y - from_y).abs() <= 2 {
+                            let block = tooltrail.pick(&view, x as f32, y as f32, 6.0 * ui_scale);
+                            tooltrail.set_highlight(block);
+                            pane.select_block(block, (area.1 as f32 / line_height) as usize);
+                        }
+                    }
+                }
+                Event::MouseMoved { x, y } => {
+                    if let Some((last_x, last_y, orbit)) = drag {
+                        let (dx, dy) = ((x - last_x) as f32, (y - last_y) as f32);
+                        if orbit {
+                            view.orbit(dx / ui_scale, dy / ui_scale);
+                        } else {
+                            view.pan(dx, dy);
+                        }
+                        drag = Some((x, y, orbit));
+                    }
+                }
+                Event::TextEntered { unicode: 'a' } | Event::TextEntered { unicode: 'A' } => {
+                    view.fit(&machine.program_bounds(), area.0, area.1)
+                }
+                Event::TextEntered { unicode: 'v' } | Event::TextEntered { unicode: 'V' } => {
+                    preset = preset.next();
+                    view.set_preset(preset);
+                    view.fit(&machine.program_bounds(), area.0, area.1);
+                }
+                Event::TextEntered { unicode: 'p' } | Event::TextEntered { unicode: 'P' } => {
+                    view.toggle_perspective()
+                }
+                // C switches between colouring by move and by depth, D shows or
+                // hides the DRO
+                Event::TextEntered { unicode: 'c' } | Event::TextEntered { unicode: 'C' } => {
+                    tooltrail.toggle_colouring()
+                }
+                Event::TextEntered { unicode: 'd' } | Event::TextEntered { unicode: 'D' } => {
+                    dro = !dro
+                }
+                // L shows or hides the listing
+                Event::TextEntered { unicode: 'l' } | Event::TextEntered { unicode: 'L' }
+                    if font.is_some() && !machine.listing().is_empty() =>
+                {
+                    listing_shown = !listing_shown;
+                    let resized = view_area(size, listing_shown);
+                    view.resize(area, resized);
+                    area = resized;
+                }
+                Event::TextEntered { unicode } => playback.handle_text(machine, unicode),
                 _ => {}
             }
         }
-        let mut toolconfig = simple_machine::ToolConfig::new();
-        match config_sync.recv() {
-            Ok(entry) => {
-                println!("GUI got config sync: {:?}", &entry);
-                toolconfig = entry;
-            }
-            Err(_) => (),
-        }
-
-        match toolstate.recv() {
-            Ok(entry) => {
-                simple_machine::SimpleMachine::update_toolstate(
-                    &entry,
-                    &toolconfig,
-                    &mut current_state,
-                );
-                tooltrail.add(&current_state);
-                sample += 1
+
+        playback.advance(machine, frame_interval);
+
+        if playback.jumped {
+            playback.jumped = false;
+            tooltrail.replay(machine.history().motion(), playback.clock);
+        }
+        while let Ok(GuiUpdate::Config(toolconfig)) = updates.try_recv() {
+            tooltrail.set_tools(&toolconfig.tools);
+        }
+
+        let next_title = playback.title(machine);
+        if next_title != title {
+            window.set_title(&next_title);
+            title = next_title;
+        }
+
+        tooltrail.follow(machine.history().motion(), playback.clock);
+        let tool = tool_at(machine, playback.clock, tooltrail.tool());
+        tooltrail.set_tool(&tool);
+        window.clear(Color::WHITE);
+        draw_lines(&mut window, &tooltrail.lines(&view));
+        if let Some(font) = &font {
+            if listing_shown {
+                let rows = (area.1 as f32 / line_height) as usize;
+                let block = machine.history().snapshots()
+                    [executing_snapshot(machine, playback.clock)]
+                .block;
+                pane.follow(block, rows);
+                let pane_area =
+                    FloatRect::new(area.0 as f32, 0.0, (size.0 - area.0) as f32, size.1 as f32);
+                pane.draw(&mut window, font, &pane_area, character_size);
             }
-            Err(_) => {
-                //println!("Unable to fetch work item: {:?}", something);
-                is_running = false;
+            if dro {
+                let lines = dro_lines(machine, playback.clock, tooltrail.tool());
+                draw_dro(&mut window, font, &lines, ui_scale);
             }
         }
+        window.display();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program: &str) -> simple_machine::SimpleMachine {
+        let mut machine = simple_machine::SimpleMachine::new(simple_machine::ToolConfig::new());
+        machine.load_str(program);
+        machine
+    }
+
+    #[test]
+    fn test_ui_scale() {
+        assert_eq!(1.0, ui_scale_for(768));
+        assert_eq!(2.0, ui_scale_for(2160));
+    }
+
+    #[test]
+    fn test_dro() {
+        let mut machine = machine("G21 G90\nS1000 M3\nM8\nT2 M6\nG1 X10 F600\nM5\n");
+        machine.set_overrides(simple_machine::Overrides {
+            feed: 50.0,
+            rapid: 100.0,
+            spindle: 100.0,
+        });
+        machine.run_to_end();
+
+        // Half way through the move, spindle and coolant still on
+        let mut trail = ToolTrail::new(&machine);
+        let clock = machine.history().end_time() - 1.0;
+        trail.follow(machine.history().motion(), clock);
+        let lines = dro_lines(&machine, clock, trail.tool());
+        assert!(lines[0].starts_with("N4 "));
+        assert!(lines[1].starts_with("X      4.") || lines[1].starts_with("X      5."));
+        assert_eq!("F600 (300) V300", lines[5]);
+        assert_eq!("S1000 (1000) M3 M8", lines[6]);
+        assert_eq!("T2 next T2", lines[7]);
+        assert_eq!("G1 G17 G21 G90 G61.1", lines[8]);
+    }
+
+    #[test]
+    fn test_listing() {
+        let machine = machine("; square\nG0 X10\nG1 Y10 F600\n\nG1 X0\nG1 Y0\n");
+        let mut pane = ListingPane::new(machine.listing());
+        assert_eq!(Some(4), pane.line_of(2));
+
+        // The executing line scrolls into view, a third down
+        pane.follow(3, 3);
+        assert_eq!((Some(5), 4), (pane.current, pane.top));
+        pane.follow(0, 3);
+        assert_eq!((Some(1), 0), (pane.current, pane.top));
+        pane.scroll(-5);
+        assert_eq!(0, pane.top);
+
+        // A line picks its move, a picked move selects its line
+        assert_eq!(Some(1), pane.click(2));
+        assert_eq!(None, pane.click(3));
+        pane.select_block(Some(3), 3);
+        assert_eq!(Some(5), pane.selected);
+    }
+
+    #[test]
+    fn test_single_block() {
+        let mut machine = machine("G1 X10 F600\nG1 X20\nG1 X30\n");
+        let mut playback = Playback::new();
+        playback.handle_text(&mut machine, 's');
+
+        // One block, played back until its end, then hold
+        for _ in 0..1000 {
+            playback.advance(&mut machine, 0.1);
+        }
+        assert!(!playback.running);
+        assert_eq!(1, machine.current_block());
+        assert_eq!(machine.toolstate().time, playback.clock);
+
+        playback.handle_text(&mut machine, ' ');
+        playback.advance(&mut machine, 0.1);
+        assert_eq!(2, machine.current_block());
+    }
+
+    #[test]
+    fn test_run_to_line_and_feed_hold() {
+        let mut machine = machine("G1 X10 F600\nG1 X20\nG1 X30\nG1 X40\n");
+        let mut playback = Playback::new();
+        playback.handle_text(&mut machine, ' ');
+        playback.advance(&mut machine, 0.1);
+        assert_eq!(0, machine.current_block());
+        assert_eq!(0.0, playback.clock);
+
+        playback.handle_text(&mut machine, '2');
+        playback.handle_text(&mut machine, '\r');
+        playback.handle_text(&mut machine, '+');
+        for _ in 0..1000 {
+            playback.advance(&mut machine, 0.1);
+        }
+        assert!(!playback.running);
+        assert_eq!(2, machine.current_block());
+        assert_eq!(20.0, machine.toolstate().x);
+        assert_eq!(2.0, playback.speed);
+    }
+
+    #[test]
+    fn test_steps_per_frame() {
+        // 1000 steps a block, a frame runs no more than the budget
+        let program: String = (1..=30)
+            .map(|n| format!("G1 X{} F6000\n", 10 * n))
+            .collect();
+        let mut machine = machine(&program);
+        let mut playback = Playback::new();
+        playback.advance(&mut machine, 1000.0);
+        assert_eq!(20, machine.current_block());
+        assert_eq!(machine.toolstate().time, playback.clock);
+        playback.advance(&mut machine, 1000.0);
+        assert_eq!(simple_machine::MachineStatus::Finished, machine.status());
+
+        // The tool comes from the motion played back
+        let mut trail = ToolTrail::new(&machine);
+        let clock = machine.history().end_time() / 2.0;
+        trail.follow(machine.history().motion(), clock);
+        let tool = tool_at(&machine, clock, trail.tool());
+        assert!((tool.x - 150.0).abs() < 0.5);
+        assert_eq!(6000.0, tool.feedrate);
+        assert_eq!(clock, tool.time);
+    }
+
+    #[test]
+    fn test_breakpoint_holds() {
+        let mut machine = machine("G1 X10 F600\nG1 X20\nG1 X30\n");
+        let mut playback = Playback::new();
+        playback.handle_text(&mut machine, '1');
+        playback.handle_text(&mut machine, 'b');
+        assert_eq!(1, machine.breakpoints().len());
+
+        for _ in 0..1000 {
+            playback.advance(&mut machine, 0.1);
+        }
+        assert!(!playback.running);
+        assert_eq!(simple_machine::MachineStatus::Break(1), machine.status());
+        assert_eq!(10.0, machine.toolstate().x);
+
+        // Resuming runs the block the breakpoint stopped before
+        playback.handle_text(&mut machine, ' ');
+        for _ in 0..1000 {
+            playback.advance(&mut machine, 0.1);
+        }
+        assert_eq!(simple_machine::MachineStatus::Finished, machine.status());
+        assert_eq!(30.0, machine.toolstate().x);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut machine = machine("S1000\nG1 X10 F600\n");
+        let mut playback = Playback::new();
+        playback.handle_text(&mut machine, '5');
+        playback.handle_text(&mut machine, '0');
+        playback.handle_text(&mut machine, 'f');
+        playback.handle_text(&mut machine, '2');
+        playback.handle_text(&mut machine, '5');
+        playback.handle_text(&mut machine, 'R');
+        assert_eq!(50.0, machine.overrides().feed);
+        assert_eq!(25.0, machine.overrides().rapid);
+
+        for _ in 0..1000 {
+            playback.advance(&mut machine, 0.1);
+        }
+        assert!(playback
+            .title(&machine)
+            .contains("F600 (300) rapid 25% S1000 (1000)"));
+
+        playback.handle_text(&mut machine, 'f');
+        assert_eq!(100.0, machine.overrides().feed);
+    }
+
+    #[test]
+    fn test_step_back_and_scrub() {
+        let mut machine = machine("G1 X10 F300\nG1 X20\nG1 X30\n");
+        let mut playback = Playback::new();
+        for _ in 0..1000 {
+            playback.advance(&mut machine, 0.1);
+        }
+        assert_eq!(simple_machine::MachineStatus::Finished, machine.status());
+
+        // Back a block, held at its start
+        playback.handle_text(&mut machine, ',');
+        assert!(!playback.running && playback.jumped);
+        assert_eq!(2, machine.current_block());
+        assert_eq!(20.0, machine.toolstate().x);
+        assert_eq!(machine.toolstate().time, playback.clock);
+
+        // A second back is still in the second move
+        playback.handle_text(&mut machine, '[');
+        assert_eq!(1, machine.current_block());
+        assert!(playback.clock > machine.toolstate().time);
+
+        playback.handle_text(&mut machine, '0');
+        playback.handle_text(&mut machine, 'g');
+        assert_eq!(0, machine.current_block());
+        assert_eq!(0.0, playback.clock);
 
-        if sample % sample_frequency == 0 {
-            tooltrail.update_texture();
-            window.clear(Color::WHITE);
-            window.draw(&tooltrail);
-            window.display()
+        // Playing on from there ends where it did before
+        playback.handle_text(&mut machine, ' ');
+        for _ in 0..1000 {
+            playback.advance(&mut machine, 0.1);
         }
+        assert_eq!(simple_machine::MachineStatus::Finished, machine.status());
+        assert_eq!(30.0, machine.toolstate().x);
     }
 }